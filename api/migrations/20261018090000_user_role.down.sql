@@ -0,0 +1 @@
+alter table iam.user drop column role;
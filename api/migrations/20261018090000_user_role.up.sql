@@ -0,0 +1,4 @@
+set search_path to pg_catalog, public;
+
+alter table iam.user add column role text not null default 'user';
+alter table iam.user add constraint user_role_chk check (role in ('user', 'administrator'));
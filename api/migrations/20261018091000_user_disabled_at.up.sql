@@ -0,0 +1,3 @@
+set search_path to pg_catalog, public;
+
+alter table iam.user add column disabled_at timestamptz;
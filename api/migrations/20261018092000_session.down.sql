@@ -0,0 +1,2 @@
+alter table iam.token drop constraint token_session_id_fk;
+drop table iam.session;
@@ -0,0 +1,20 @@
+set search_path to pg_catalog, public;
+
+create table iam.session (
+    session__id uuid not null primary key default public.gen_random_uuid(),
+    user__id uuid not null,
+    created_at timestamptz not null default statement_timestamp(),
+    last_refreshed_at timestamptz,
+    user_agent text,
+    ip_address inet,
+    constraint session_user__id_fk foreign key (user__id) references iam.user (user__id) on delete cascade on update cascade
+);
+
+insert into iam.session (session__id, user__id, created_at)
+    select session_id, user__id, min(created_at)
+    from iam.token
+    where session_id is not null and user__id is not null
+    group by session_id, user__id;
+
+alter table iam.token
+    add constraint token_session_id_fk foreign key (session_id) references iam.session (session__id) on delete cascade on update cascade;
@@ -0,0 +1,2 @@
+drop table iam.security_event;
+alter table iam.token drop column rotated_at;
@@ -0,0 +1,16 @@
+set search_path to pg_catalog, public;
+
+alter table iam.token add column rotated_at timestamptz;
+
+create table iam.security_event (
+    security_event__id uuid not null primary key default public.gen_random_uuid(),
+    created_at timestamptz not null default statement_timestamp(),
+    type text not null,
+    user__id uuid,
+    session_id uuid,
+    ip_address inet,
+    details jsonb,
+    constraint security_event_user__id_fk foreign key (user__id) references iam.user (user__id) on delete cascade on update cascade
+);
+
+create index security_event_user__id_idx on iam.security_event (user__id);
@@ -0,0 +1,10 @@
+delete from iam.token where type = 'mfa_pending';
+alter table iam.token drop constraint token_mfa_pending_user__id_chk;
+alter table iam.token drop constraint token_type_chk;
+alter table iam.token add constraint token_type_chk check (type in ('user_access', 'refresh'));
+
+drop table iam.recovery_code;
+
+alter table iam.user drop constraint user_totp_enabled_at_chk;
+alter table iam.user drop column totp_enabled_at;
+alter table iam.user drop column totp_secret;
@@ -0,0 +1,20 @@
+set search_path to pg_catalog, public;
+
+alter table iam.user add column totp_secret bytea;
+alter table iam.user add column totp_enabled_at timestamptz;
+alter table iam.user add constraint user_totp_enabled_at_chk check (totp_enabled_at is null or totp_secret is not null);
+
+create table iam.recovery_code (
+    recovery_code__id uuid not null primary key default public.gen_random_uuid(),
+    user__id uuid not null,
+    code_hash text not null,
+    created_at timestamptz not null default statement_timestamp(),
+    used_at timestamptz,
+    constraint recovery_code_user__id_fk foreign key (user__id) references iam.user (user__id) on delete cascade on update cascade
+);
+
+create index recovery_code_user__id_idx on iam.recovery_code (user__id);
+
+alter table iam.token drop constraint token_type_chk;
+alter table iam.token add constraint token_type_chk check (type in ('user_access', 'refresh', 'mfa_pending'));
+alter table iam.token add constraint token_mfa_pending_user__id_chk check (type <> 'mfa_pending' or user__id is not null);
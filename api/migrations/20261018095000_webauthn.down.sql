@@ -0,0 +1,2 @@
+drop table iam.webauthn_challenge;
+drop table iam.webauthn_credential;
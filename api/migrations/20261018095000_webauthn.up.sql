@@ -0,0 +1,24 @@
+set search_path to pg_catalog, public;
+
+create table iam.webauthn_credential (
+    webauthn_credential__id uuid not null primary key default public.gen_random_uuid(),
+    user__id uuid not null,
+    name text not null,
+    passkey jsonb not null,
+    created_at timestamptz not null default statement_timestamp(),
+    last_used_at timestamptz,
+    constraint webauthn_credential_user__id_fk foreign key (user__id) references iam.user (user__id) on delete cascade on update cascade
+);
+
+create index webauthn_credential_user__id_idx on iam.webauthn_credential (user__id);
+
+create table iam.webauthn_challenge (
+    webauthn_challenge__id uuid not null primary key default public.gen_random_uuid(),
+    type text not null,
+    user__id uuid not null,
+    state jsonb not null,
+    created_at timestamptz not null default statement_timestamp(),
+    expired_at timestamptz not null,
+    constraint webauthn_challenge_type_chk check (type in ('registration', 'authentication')),
+    constraint webauthn_challenge_user__id_fk foreign key (user__id) references iam.user (user__id) on delete cascade on update cascade
+);
@@ -0,0 +1 @@
+drop table iam.auth_attempt;
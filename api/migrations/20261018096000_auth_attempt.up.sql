@@ -0,0 +1,14 @@
+set search_path to pg_catalog, public;
+
+create table iam.auth_attempt (
+    auth_attempt__id uuid not null primary key default public.gen_random_uuid(),
+    created_at timestamptz not null default statement_timestamp(),
+    type text not null,
+    email text not null,
+    ip_address inet,
+    succeeded boolean not null,
+    constraint auth_attempt_type_chk check (type in ('login', 'reset_password'))
+);
+
+create index auth_attempt_email_idx on iam.auth_attempt (type, email, created_at);
+create index auth_attempt_ip_address_idx on iam.auth_attempt (type, ip_address, created_at);
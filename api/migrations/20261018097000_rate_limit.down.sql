@@ -0,0 +1,2 @@
+drop table infrastructure.rate_limit_bucket;
+drop schema infrastructure;
@@ -0,0 +1,13 @@
+set search_path to pg_catalog, public;
+
+create schema infrastructure;
+
+create unlogged table infrastructure.rate_limit_bucket (
+    key text not null primary key,
+    tokens double precision not null,
+    allowed boolean not null,
+    updated_at timestamptz not null,
+    refilled_at timestamptz not null
+);
+
+create index rate_limit_bucket_refilled_at_idx on infrastructure.rate_limit_bucket (refilled_at);
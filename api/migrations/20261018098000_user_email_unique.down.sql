@@ -0,0 +1 @@
+drop index iam.user_email_key;
@@ -0,0 +1,20 @@
+set search_path to pg_catalog, public;
+
+do $$
+begin
+    if exists (
+        select 1
+        from iam.user
+        group by lower(trim(email))
+        having count(*) > 1
+    ) then
+        raise exception 'Some email addresses are used by several users (case-insensitively); merge or delete these users before running this migration';
+    end if;
+end
+$$;
+
+update iam.user
+set email = lower(trim(email))
+where email <> lower(trim(email));
+
+create unique index user_email_key on iam.user (lower(email));
@@ -0,0 +1,6 @@
+set search_path to pg_catalog, public;
+
+alter table iam.user drop column timezone;
+alter table iam.user drop column locale;
+alter table iam.user drop column bio;
+alter table iam.user drop column display_name;
@@ -0,0 +1,10 @@
+set search_path to pg_catalog, public;
+
+alter table iam.user add column display_name text;
+alter table iam.user add column bio text;
+alter table iam.user add column locale text;
+alter table iam.user add column timezone text;
+alter table iam.user add constraint user_display_name_chk check (display_name is null or length(display_name) between 1 and 50);
+alter table iam.user add constraint user_bio_chk check (bio is null or length(bio) between 1 and 500);
+alter table iam.user add constraint user_locale_chk check (locale is null or length(locale) between 2 and 35);
+alter table iam.user add constraint user_timezone_chk check (timezone is null or length(timezone) between 1 and 50);
@@ -0,0 +1,6 @@
+set search_path to pg_catalog, public;
+
+drop trigger user_rotate_security_stamp_trg on iam.user;
+drop function iam.user_rotate_security_stamp();
+
+alter table iam.user drop column security_stamp;
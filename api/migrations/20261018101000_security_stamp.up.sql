@@ -0,0 +1,24 @@
+set search_path to pg_catalog, public;
+
+alter table iam.user add column security_stamp uuid not null default public.gen_random_uuid();
+
+-- Access tokens carry the security stamp of their user and are rejected once it changes
+create function iam.user_rotate_security_stamp() returns trigger language plpgsql as $$
+begin
+    new.security_stamp := public.gen_random_uuid();
+    return new;
+end;
+$$;
+
+create trigger user_rotate_security_stamp_trg
+    before update of email, password, first_name, last_name, role, disabled_at on iam.user
+    for each row
+    when (
+        old.email is distinct from new.email
+        or old.password is distinct from new.password
+        or old.first_name is distinct from new.first_name
+        or old.last_name is distinct from new.last_name
+        or old.role is distinct from new.role
+        or old.disabled_at is distinct from new.disabled_at
+    )
+    execute function iam.user_rotate_security_stamp();
@@ -0,0 +1,5 @@
+set search_path to pg_catalog, public;
+
+drop index iam.user_deletion_requested_at_idx;
+
+alter table iam.user drop column deletion_requested_at;
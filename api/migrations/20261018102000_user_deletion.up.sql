@@ -0,0 +1,5 @@
+set search_path to pg_catalog, public;
+
+alter table iam.user add column deletion_requested_at timestamptz;
+
+create index user_deletion_requested_at_idx on iam.user (deletion_requested_at) where deletion_requested_at is not null;
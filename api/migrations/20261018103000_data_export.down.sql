@@ -0,0 +1,3 @@
+set search_path to pg_catalog, public;
+
+drop table iam.data_export;
\ No newline at end of file
@@ -0,0 +1,15 @@
+set search_path to pg_catalog, public;
+
+create table iam.data_export (
+    data_export__id uuid not null primary key default public.gen_random_uuid(),
+    user__id uuid,
+    created_at timestamptz not null default statement_timestamp(),
+    completed_at timestamptz,
+    failed_at timestamptz,
+    expired_at timestamptz not null,
+    -- Archives of deleted users are kept until they are removed from the storage by the purge job
+    constraint data_export_user__id_fk foreign key (user__id) references iam.user (user__id) on delete set null on update cascade
+);
+
+create index data_export_user__id_idx on iam.data_export (user__id, created_at);
+create index data_export_expired_at_idx on iam.data_export (expired_at);
\ No newline at end of file
@@ -0,0 +1,3 @@
+set search_path to pg_catalog, public;
+
+drop table infrastructure.mail_outbox;
\ No newline at end of file
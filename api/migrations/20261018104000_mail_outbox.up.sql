@@ -0,0 +1,23 @@
+set search_path to pg_catalog, public;
+
+create table infrastructure.mail_outbox (
+    mail_outbox__id uuid not null primary key default public.gen_random_uuid(),
+    created_at timestamptz not null default statement_timestamp(),
+    kind text not null,
+    recipient text not null,
+    subject text not null,
+    html_body text,
+    text_body text,
+    status text not null default 'pending',
+    attempts integer not null default 0,
+    next_attempt_at timestamptz not null default statement_timestamp(),
+    last_error text,
+    sent_at timestamptz,
+    constraint mail_outbox_status_chk check (status in ('pending', 'sent', 'dead')),
+    -- Bodies contain links with tokens, so they are only kept until the mail is sent
+    constraint mail_outbox_body_chk check (status = 'sent' or (html_body is not null and text_body is not null)),
+    constraint mail_outbox_sent_at_chk check ((status = 'sent') = (sent_at is not null))
+);
+
+create index mail_outbox_next_attempt_at_idx on infrastructure.mail_outbox (next_attempt_at) where status = 'pending';
+create index mail_outbox_sent_at_idx on infrastructure.mail_outbox (sent_at) where status = 'sent';
\ No newline at end of file
@@ -0,0 +1,5 @@
+set search_path to pg_catalog, public;
+
+alter table iam.token drop constraint token_type_chk;
+alter table iam.token add constraint token_type_chk check (type in ('user_access', 'refresh', 'mfa_pending', 'email_change', 'email_change_undo'));
+alter table iam.token add constraint token_email_change_undo_user__id_chk check (type <> 'email_change_undo' or user__id is not null);
\ No newline at end of file
@@ -0,0 +1,3 @@
+set search_path to pg_catalog, public;
+
+drop index iam.auth_attempt_created_at_idx;
\ No newline at end of file
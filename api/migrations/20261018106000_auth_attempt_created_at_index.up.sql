@@ -0,0 +1,3 @@
+set search_path to pg_catalog, public;
+
+create index auth_attempt_created_at_idx on iam.auth_attempt (created_at);
\ No newline at end of file
@@ -0,0 +1,3 @@
+set search_path to pg_catalog, public;
+
+drop index iam.user_email_idx;
\ No newline at end of file
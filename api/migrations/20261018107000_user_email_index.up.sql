@@ -0,0 +1,4 @@
+set search_path to pg_catalog, public;
+
+-- Addresses are normalized before being stored, so lookups use the column itself; user_email_key still enforces case-insensitive uniqueness
+create index user_email_idx on iam.user (email);
\ No newline at end of file
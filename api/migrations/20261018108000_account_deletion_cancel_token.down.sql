@@ -0,0 +1,6 @@
+set search_path to pg_catalog, public;
+
+delete from iam.token where type = 'account_deletion_cancel';
+alter table iam.token drop constraint token_account_deletion_cancel_user__id_chk;
+alter table iam.token drop constraint token_type_chk;
+alter table iam.token add constraint token_type_chk check (type in ('user_access', 'refresh', 'mfa_pending', 'email_change', 'email_change_undo'));
\ No newline at end of file
@@ -0,0 +1,5 @@
+set search_path to pg_catalog, public;
+
+alter table iam.token drop constraint token_type_chk;
+alter table iam.token add constraint token_type_chk check (type in ('user_access', 'refresh', 'mfa_pending', 'email_change', 'email_change_undo', 'account_deletion_cancel'));
+alter table iam.token add constraint token_account_deletion_cancel_user__id_chk check (type <> 'account_deletion_cancel' or user__id is not null);
\ No newline at end of file
@@ -0,0 +1,3 @@
+set search_path to pg_catalog, public;
+
+alter table iam.user drop column profile_picture_version;
\ No newline at end of file
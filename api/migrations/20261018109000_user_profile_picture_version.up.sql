@@ -0,0 +1,4 @@
+set search_path to pg_catalog, public;
+
+-- Every size and format of a profile picture is stored under this version; null if the user has no profile picture
+alter table iam.user add column profile_picture_version uuid;
\ No newline at end of file
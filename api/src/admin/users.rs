@@ -0,0 +1,402 @@
+use actix_web::web::ReqData;
+use biscuit_auth::Biscuit;
+use chrono::{DateTime, Utc};
+use log::error;
+use paperclip::actix::web::{Data, Json, Path, Query};
+use paperclip::actix::{api_v2_operation, Apiv2Schema, NoContent};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, query_scalar};
+use std::str::FromStr;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::auth::auth::send_reset_password_email;
+use crate::auth::iam::{authorize_only_user, Action, Role};
+use crate::auth::sessions::revoke_user_sessions;
+use crate::users_settings::profile_picture::discard_profile_pictures;
+use crate::utils::locale::Locale;
+use crate::utils::openapi::OaBiscuitUserAccess;
+use crate::utils::problems::MyProblem;
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+
+#[derive(Debug, Deserialize, Apiv2Schema, Validate)]
+pub struct ListUsersQs {
+    /// Only return users whose email contains this value (case-insensitive)
+    #[validate(non_control_character, length(min = 1, max = 100))]
+    email: Option<String>,
+    /// Only return users whose email is (or is not) verified
+    verified: Option<bool>,
+    /// Only return users created at or after this date
+    created_after: Option<DateTime<Utc>>,
+    /// Only return users created before this date
+    created_before: Option<DateTime<Utc>>,
+    #[validate(range(min = 1))]
+    page: Option<i64>,
+    #[validate(range(min = 1, max = 100))]
+    per_page: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct UserRow {
+    user_id: Uuid,
+    email: String,
+    first_name: String,
+    last_name: String,
+    role: String,
+    created_at: DateTime<Utc>,
+    email_verified_at: Option<DateTime<Utc>>,
+    last_login: Option<DateTime<Utc>>,
+    disabled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Apiv2Schema)]
+pub struct User {
+    user_id: Uuid,
+    email: String,
+    first_name: String,
+    last_name: String,
+    role: Role,
+    created_at: DateTime<Utc>,
+    email_verified_at: Option<DateTime<Utc>>,
+    last_login: Option<DateTime<Utc>>,
+    disabled_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<UserRow> for User {
+    type Error = MyProblem;
+
+    fn try_from(row: UserRow) -> Result<Self, Self::Error> {
+        let role = Role::from_str(&row.role).map_err(|e| {
+            error!("Role of user {} is invalid: {e}", &row.user_id);
+            MyProblem::InternalServerError
+        })?;
+
+        Ok(User {
+            user_id: row.user_id,
+            email: row.email,
+            first_name: row.first_name,
+            last_name: row.last_name,
+            role,
+            created_at: row.created_at,
+            email_verified_at: row.email_verified_at,
+            last_login: row.last_login,
+            disabled_at: row.disabled_at,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Apiv2Schema)]
+pub struct UserList {
+    users: Vec<User>,
+    total: i64,
+    page: i64,
+    per_page: i64,
+}
+
+#[api_v2_operation(
+    summary = "List users",
+    description = "List users, optionally filtered by email, verification status and creation date.",
+    operation_id = "admin.list_users",
+    produces = "application/json",
+    tags("Administration")
+)]
+pub async fn list_users(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+    qs: Query<ListUsersQs>,
+) -> Result<Json<UserList>, MyProblem> {
+    if let Err(e) = qs.validate() {
+        return Err(MyProblem::Validation(e));
+    }
+
+    if authorize_only_user(&biscuit, Action::AdminListUsers).is_ok() {
+        let page = qs.page.unwrap_or(1);
+        let per_page = qs.per_page.unwrap_or(DEFAULT_PAGE_SIZE);
+
+        let total = query_scalar!(
+            r#"
+                SELECT count(*) AS "total!"
+                FROM iam.user
+                WHERE ($1::text IS NULL OR strpos(lower(email), lower($1)) > 0)
+                    AND ($2::boolean IS NULL OR (email_verified_at IS NOT NULL) = $2)
+                    AND ($3::timestamptz IS NULL OR created_at >= $3)
+                    AND ($4::timestamptz IS NULL OR created_at < $4)
+            "#,
+            qs.email.as_deref(),
+            qs.verified,
+            qs.created_after,
+            qs.created_before,
+        )
+        .fetch_one(&state.db)
+        .await?;
+
+        let rows = query_as!(
+            UserRow,
+            "
+                SELECT user__id AS user_id, email, first_name, last_name, role, created_at, email_verified_at, last_login, disabled_at
+                FROM iam.user
+                WHERE ($1::text IS NULL OR strpos(lower(email), lower($1)) > 0)
+                    AND ($2::boolean IS NULL OR (email_verified_at IS NOT NULL) = $2)
+                    AND ($3::timestamptz IS NULL OR created_at >= $3)
+                    AND ($4::timestamptz IS NULL OR created_at < $4)
+                ORDER BY created_at DESC, user__id
+                LIMIT $5
+                OFFSET $6
+            ",
+            qs.email.as_deref(),
+            qs.verified,
+            qs.created_after,
+            qs.created_before,
+            per_page,
+            (page - 1) * per_page,
+        )
+        .fetch_all(&state.db)
+        .await?;
+
+        let users = rows
+            .into_iter()
+            .map(User::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Json(UserList {
+            users,
+            total,
+            page,
+            per_page,
+        }))
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Get a user",
+    description = "Get the details of a user.",
+    operation_id = "admin.get_user",
+    produces = "application/json",
+    tags("Administration")
+)]
+pub async fn get_user(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+    user_id: Path<Uuid>,
+) -> Result<Json<User>, MyProblem> {
+    if authorize_only_user(&biscuit, Action::AdminGetUser).is_ok() {
+        let row = query_as!(
+            UserRow,
+            "
+                SELECT user__id AS user_id, email, first_name, last_name, role, created_at, email_verified_at, last_login, disabled_at
+                FROM iam.user
+                WHERE user__id = $1
+            ",
+            &user_id.into_inner(),
+        )
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or(MyProblem::NotFound)?;
+
+        Ok(Json(User::try_from(row)?))
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Verify the email of a user",
+    description = "Mark the email of a user as verified without requiring them to click the verification link.",
+    operation_id = "admin.verify_user_email",
+    produces = "application/json",
+    tags("Administration")
+)]
+pub async fn verify_user_email(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+    user_id: Path<Uuid>,
+) -> Result<NoContent, MyProblem> {
+    if authorize_only_user(&biscuit, Action::AdminVerifyUserEmail).is_ok() {
+        query!(
+            "
+                UPDATE iam.user
+                SET email_verified_at = COALESCE(email_verified_at, statement_timestamp())
+                WHERE user__id = $1
+                RETURNING user__id
+            ",
+            &user_id.into_inner(),
+        )
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or(MyProblem::NotFound)?;
+
+        Ok(NoContent)
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Send a reset password email to a user",
+    description = "Send an email with a link to reset the password of a user.",
+    operation_id = "admin.send_reset_password_email",
+    produces = "application/json",
+    tags("Administration")
+)]
+pub async fn send_user_reset_password_email(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+    user_id: Path<Uuid>,
+) -> Result<NoContent, MyProblem> {
+    if authorize_only_user(&biscuit, Action::AdminSendResetPasswordEmail).is_ok() {
+        let user = query!(
+            "
+                SELECT user__id AS user_id, email, first_name, last_name, locale
+                FROM iam.user
+                WHERE user__id = $1
+            ",
+            &user_id.into_inner(),
+        )
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or(MyProblem::NotFound)?;
+
+        send_reset_password_email(
+            &state,
+            user.user_id,
+            &user.email,
+            &user.first_name,
+            &user.last_name,
+            // The mail is meant for the user, not for the administrator making the request
+            Locale::from_stored(user.locale.as_deref()),
+        )
+        .await?;
+
+        Ok(NoContent)
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Disable a user",
+    description = "Prevent a user from logging in and revoke all their sessions.",
+    operation_id = "admin.disable_user",
+    produces = "application/json",
+    tags("Administration")
+)]
+pub async fn disable_user(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+    user_id: Path<Uuid>,
+) -> Result<NoContent, MyProblem> {
+    if let Ok(token) = authorize_only_user(&biscuit, Action::AdminDisableUser) {
+        let user_id = user_id.into_inner();
+
+        if user_id == token.user_id {
+            // Administrators must not be able to lock themselves out
+            return Err(MyProblem::Forbidden);
+        }
+
+        let mut tx = state.db.begin().await?;
+
+        query!(
+            "
+                UPDATE iam.user
+                SET disabled_at = COALESCE(disabled_at, statement_timestamp())
+                WHERE user__id = $1
+                RETURNING user__id
+            ",
+            &user_id,
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(MyProblem::NotFound)?;
+
+        revoke_user_sessions(&mut *tx, user_id, None).await?;
+
+        tx.commit().await?;
+        Ok(NoContent)
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Enable a user",
+    description = "Allow a previously disabled user to log in again.",
+    operation_id = "admin.enable_user",
+    produces = "application/json",
+    tags("Administration")
+)]
+pub async fn enable_user(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+    user_id: Path<Uuid>,
+) -> Result<NoContent, MyProblem> {
+    if authorize_only_user(&biscuit, Action::AdminEnableUser).is_ok() {
+        query!(
+            "
+                UPDATE iam.user
+                SET disabled_at = NULL
+                WHERE user__id = $1
+                RETURNING user__id
+            ",
+            &user_id.into_inner(),
+        )
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or(MyProblem::NotFound)?;
+
+        Ok(NoContent)
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Delete a user",
+    description = "Permanently delete a user and all their information.",
+    operation_id = "admin.delete_user",
+    produces = "application/json",
+    tags("Administration")
+)]
+pub async fn delete_user(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+    user_id: Path<Uuid>,
+) -> Result<NoContent, MyProblem> {
+    if let Ok(token) = authorize_only_user(&biscuit, Action::AdminDeleteUser) {
+        let user_id = user_id.into_inner();
+
+        if user_id == token.user_id {
+            // Administrators must delete their own account through the user settings
+            return Err(MyProblem::Forbidden);
+        }
+
+        let deleted_user = query!(
+            "
+                DELETE FROM iam.user
+                WHERE user__id = $1
+                RETURNING profile_picture_version
+            ",
+            &user_id,
+        )
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or(MyProblem::NotFound)?;
+
+        if let Some(version) = deleted_user.profile_picture_version {
+            discard_profile_pictures(state.storage.as_ref(), user_id, version).await;
+        }
+
+        Ok(NoContent)
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
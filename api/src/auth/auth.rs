@@ -1,4 +1,5 @@
 use actix_web::web::ReqData;
+use actix_web::HttpRequest;
 use argon2::password_hash::PasswordHashString;
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use biscuit_auth::{Biscuit, PrivateKey};
@@ -11,17 +12,24 @@ use paperclip::actix::{api_v2_operation, Apiv2Schema, CreatedJson, NoContent};
 use serde::{Deserialize, Serialize};
 use sqlx::{query, query_as, query_scalar, Acquire, Postgres};
 use std::str::FromStr;
+use std::sync::OnceLock;
 use uuid::Uuid;
 use validator::Validate;
 
+use crate::utils::client_info::ClientInfo;
+use crate::utils::email::normalize_email;
+use crate::utils::locale::Locale;
 use crate::utils::mailer::Mail;
 use crate::utils::problems::MyProblem;
 use crate::auth::iam::{
-    authorize_email_verification, authorize_only_user, authorize_refresh_token, create_refresh_token, create_reset_password_token, create_user_access_token, authorize_reset_password, Action
+    authorize_account_deletion_cancel, authorize_email_change, authorize_email_change_undo, authorize_email_verification, authorize_only_user, authorize_refresh_token, create_refresh_token, create_reset_password_token, create_user_access_token, authorize_reset_password, Action, Role
 };
 use crate::utils::openapi::{OaBiscuitRefresh, OaBiscuitUserAccess};
 
+use super::brute_force::{check_login_attempts, record_login_attempt, throttle_reset_password};
 use super::iam::{create_email_verification_token, get_user_id_from_expired_email_verification};
+use super::mfa::start_mfa_challenge;
+use super::sessions::{revoke_session_after_token_reuse, revoke_user_sessions};
 
 #[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
 pub struct LoginPost {
@@ -32,13 +40,19 @@ pub struct LoginPost {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-struct UserLookup {
+pub(crate) struct UserLookup {
     user_id: Uuid,
     password_hash: String,
-    email: String,
+    pub(crate) email: String,
     first_name: String,
     last_name: String,
     email_verified_at: Option<DateTime<Utc>>,
+    role: String,
+    disabled_at: Option<DateTime<Utc>>,
+    totp_enabled_at: Option<DateTime<Utc>>,
+    security_stamp: Uuid,
+    deletion_requested_at: Option<DateTime<Utc>>,
+    locale: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Apiv2Schema)]
@@ -51,6 +65,7 @@ pub struct LoginResponse {
     email: String,
     first_name: String,
     last_name: String,
+    role: Role,
 }
 
 #[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
@@ -79,15 +94,29 @@ pub struct ResetPasswordPost {
     new_password: String,
 }
 
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
+pub struct EmailChangePost {
+    #[validate(non_control_character, length(min = 1, max = 1000))]
+    token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
+pub struct AccountDeletionCancelPost {
+    #[validate(non_control_character, length(min = 1, max = 1000))]
+    token: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
 pub struct ChangePasswordPost {
+    #[validate(non_control_character, length(min = 1, max = 100))]
+    current_password: String,
     #[validate(non_control_character, length(min = 10, max = 100))]
     new_password: String,
 }
 
 #[api_v2_operation(
     summary = "Login",
-    description = "Get an access token using a user's credentials.",
+    description = "Get an access token using a user's credentials. If the user enabled two-factor authentication, this fails with an `AuthMfaRequired` problem containing an `mfa_token` that must be exchanged using the `auth.mfa_verify` operation. After too many failed attempts for an account or from an IP address, logins are temporarily blocked.",
     operation_id = "auth.login",
     consumes = "application/json",
     produces = "application/json",
@@ -95,60 +124,128 @@ pub struct ChangePasswordPost {
 )]
 pub async fn login(
     state: Data<crate::State>,
+    req: HttpRequest,
     body: Json<LoginPost>,
 ) -> Result<CreatedJson<LoginResponse>, MyProblem> {
     if let Err(e) = body.validate() {
         return Err(MyProblem::Validation(e));
     }
 
+    let email = normalize_email(&body.email);
+    let client = ClientInfo::from_request(&req, state.trust_proxy_headers);
+    check_login_attempts(&state.db, &state.auth_attempt_limits, &email, &client).await?;
+
     let user_lookup = query_as!(
         UserLookup,
         "
-            SELECT user__id AS user_id, password AS password_hash, email, first_name, last_name, email_verified_at
+            SELECT user__id AS user_id, password AS password_hash, email, first_name, last_name, email_verified_at, role, disabled_at, totp_enabled_at, security_stamp, deletion_requested_at, locale
             FROM iam.user
             WHERE email = $1
         ",
-        &body.email,
+        &email,
     )
     .fetch_optional(&state.db)
     .await
     .map_err(MyProblem::from)?;
 
     if let Some(user) = user_lookup {
-        if user.email_verified_at.is_some() {
-            let password_hash = PasswordHash::new(&user.password_hash).map_err(|e| {
-                error!(
-                    "Password hash of user {} is not in the right format: {e}",
-                    &user.user_id
-                );
-                MyProblem::InternalServerError
-            })?;
-
-            if Argon2::default()
-                .verify_password(body.password.as_bytes(), &password_hash)
-                .is_ok()
-            {
-                do_login(&state.db, &state.biscuit_private_key, user, None).await
+        // The password is checked first so that unverified accounts cannot be told apart from unknown ones
+        if check_password(&body.password, &user.password_hash, user.user_id)? {
+            if user.email_verified_at.is_none() {
+                Err(MyProblem::EmailNotVerified)
+            } else if user.totp_enabled_at.is_some() {
+                // The login only succeeds once the second factor is verified, so failed attempts keep counting until then
+                let mfa_token = start_mfa_challenge(&state, user.user_id).await?;
+                Err(MyProblem::AuthMfaRequired(mfa_token))
             } else {
-                Err(MyProblem::AuthFailedLogin)
+                let res = do_login(&state.db, &state.biscuit_private_key, user, None, &client).await?;
+                record_login_attempt(&state, &email, &client, true).await?;
+                Ok(res)
             }
         } else {
-            Err(MyProblem::EmailNotVerified)
+            record_login_attempt(&state, &email, &client, false).await?;
+            Err(MyProblem::AuthFailedLogin)
         }
     } else {
+        check_dummy_password(&body.password);
+        record_login_attempt(&state, &email, &client, false).await?;
         Err(MyProblem::AuthFailedLogin)
     }
 }
 
-async fn do_login<'a, A: Acquire<'a, Database = Postgres>>(
+pub(crate) async fn lookup_user<'a, A: Acquire<'a, Database = Postgres>>(
+    db: A,
+    user_id: Uuid,
+) -> Result<UserLookup, MyProblem> {
+    let mut db = db.acquire().await?;
+
+    let user = query_as!(
+        UserLookup,
+        "
+            SELECT user__id AS user_id, password AS password_hash, email, first_name, last_name, email_verified_at, role, disabled_at, totp_enabled_at, security_stamp, deletion_requested_at, locale
+            FROM iam.user
+            WHERE user__id = $1
+        ",
+        &user_id,
+    )
+    .fetch_one(&mut *db)
+    .await?;
+
+    Ok(user)
+}
+
+pub(crate) async fn do_login<'a, A: Acquire<'a, Database = Postgres>>(
     db: A,
     biscuit_private_key: &PrivateKey,
     user: UserLookup,
     session_id: Option<Uuid>,
+    client: &ClientInfo,
 ) -> Result<CreatedJson<LoginResponse>, MyProblem> {
+    if user.disabled_at.is_some() {
+        return Err(MyProblem::AuthAccountDisabled);
+    }
+
+    if user.deletion_requested_at.is_some() {
+        return Err(MyProblem::AuthAccountPendingDeletion);
+    }
+
     let mut db = db.acquire().await?;
 
-    let session_id = session_id.unwrap_or_else(Uuid::new_v4);
+    let role = Role::from_str(&user.role).map_err(|e| {
+        error!("Role of user {} is invalid: {e}", &user.user_id);
+        MyProblem::InternalServerError
+    })?;
+
+    let session_id = if let Some(session_id) = session_id {
+        query!(
+            "
+                UPDATE iam.session
+                SET last_refreshed_at = statement_timestamp(), user_agent = $2, ip_address = $3
+                WHERE session__id = $1
+            ",
+            &session_id,
+            client.user_agent.as_deref(),
+            client.ip,
+        )
+        .execute(&mut *db)
+        .await?;
+
+        session_id
+    } else {
+        query_scalar!(
+            "
+                INSERT INTO iam.session (user__id, user_agent, ip_address)
+                VALUES ($1, $2, $3)
+                RETURNING session__id
+            ",
+            &user.user_id,
+            client.user_agent.as_deref(),
+            client.ip,
+        )
+        .fetch_one(&mut *db)
+        .await?
+    };
+
     let access_token_id = Uuid::new_v4();
     let (access_token, access_token_expiration) = create_user_access_token(
         biscuit_private_key,
@@ -158,6 +255,8 @@ async fn do_login<'a, A: Acquire<'a, Database = Postgres>>(
         &user.email,
         &user.first_name,
         &user.last_name,
+        role,
+        user.security_stamp,
     )
     .and_then(|rt| {
         if let Some(expired_at) = rt.expired_at {
@@ -229,12 +328,13 @@ async fn do_login<'a, A: Acquire<'a, Database = Postgres>>(
         email: user.email,
         first_name: user.first_name,
         last_name: user.last_name,
+        role,
     }))
 }
 
 #[api_v2_operation(
     summary = "Refresh access token",
-    description = "Get a new access token in exchange of a refresh token.",
+    description = "Get a new access token in exchange of a refresh token. Refresh tokens can only be used once: presenting an already used refresh token revokes the whole session.",
     operation_id = "auth.refresh",
     consumes = "application/json",
     produces = "application/json",
@@ -243,15 +343,17 @@ async fn do_login<'a, A: Acquire<'a, Database = Postgres>>(
 pub async fn refresh(
     state: Data<crate::State>,
     _: OaBiscuitRefresh,
+    req: HttpRequest,
     biscuit: ReqData<Biscuit>,
 ) -> Result<CreatedJson<LoginResponse>, MyProblem> {
     if let Ok(token) = authorize_refresh_token(&biscuit) {
+        let client = ClientInfo::from_request(&req, state.trust_proxy_headers);
         let mut tx = state.db.begin().await?;
 
-        query!(
+        let rotated = query!(
             "
                 UPDATE iam.token
-                SET expired_at = statement_timestamp()
+                SET expired_at = statement_timestamp(), rotated_at = statement_timestamp()
                 WHERE token__id = $1
                     AND type = 'refresh'
                     AND expired_at > statement_timestamp()
@@ -259,26 +361,31 @@ pub async fn refresh(
             &token.token_id,
         )
         .execute(&mut *tx)
-        .await?;
+        .await?
+        .rows_affected();
 
-        let user = query_as!(
-            UserLookup,
-            "
-                SELECT user__id AS user_id, password AS password_hash, email, first_name, last_name, email_verified_at
-                FROM iam.user
-                WHERE user__id = $1
-            ",
-            &token.user_id,
-        )
-        .fetch_one(&state.db)
-        .await
-        .map_err(MyProblem::from)?;
+        if rotated == 0 {
+            // The refresh token was rotated by a concurrent request after it went through the auth middleware
+            tx.rollback().await?;
+            revoke_session_after_token_reuse(
+                &state.db,
+                token.token_id,
+                token.session_id,
+                token.user_id,
+                &client,
+            )
+            .await?;
+            return Err(MyProblem::AuthFailedRefresh);
+        }
+
+        let user = lookup_user(&state.db, token.user_id).await?;
 
         let res = do_login(
             &mut tx,
             &state.biscuit_private_key,
             user,
             Some(token.session_id),
+            &client,
         )
         .await?;
         tx.commit().await?;
@@ -385,6 +492,7 @@ pub async fn verify_email(
 )]
 pub async fn resend_email_verification(
     state: Data<crate::State>,
+    req: HttpRequest,
     body: Json<ResendEmailVerificationPost>,
 ) -> Result<NoContent, MyProblem> {
     if let Err(e) = body.validate() {
@@ -403,7 +511,7 @@ pub async fn resend_email_verification(
         let user_lookup = query_as!(
             UserLookup,
             "
-                SELECT user__id AS user_id, email, first_name, last_name, email_verified_at, password AS password_hash
+                SELECT user__id AS user_id, email, first_name, last_name, email_verified_at, password AS password_hash, role, disabled_at, totp_enabled_at, security_stamp, deletion_requested_at, locale
                 FROM iam.user
                 WHERE user__id = $1 AND email_verified_at IS NULL
             ",
@@ -431,7 +539,8 @@ pub async fn resend_email_verification(
             );
             state
                 .mailer
-                .send_mail(
+                .queue_mail(
+                    &state.db,
                     Mail::VerifyUserEmail {
                         url: format!(
                             "{}verify-email?token={}",
@@ -439,12 +548,9 @@ pub async fn resend_email_verification(
                         ),
                     },
                     recipient,
+                    Locale::negotiate(user.locale.as_deref(), &req),
                 )
-                .await
-                .map_err(|e| {
-                    warn!("Could not send verification email: {e}");
-                    e
-                })?;
+                .await?;
     
     
             Ok(NoContent)
@@ -460,7 +566,7 @@ pub async fn resend_email_verification(
 
 #[api_v2_operation(
     summary = "Begin reset password",
-    description = "Send an email with a link to reset the password of a user.",
+    description = "Send an email with a link to reset the password of a user. The number of emails that can be requested for an account is limited.",
     operation_id = "auth.begin_reset_password",
     consumes = "application/json",
     produces = "application/json",
@@ -468,76 +574,100 @@ pub async fn resend_email_verification(
 )]
 pub async fn begin_reset_password(
     state: Data<crate::State>,
+    req: HttpRequest,
     body: Json<BeginResetPasswordPost>,
 ) -> Result<NoContent, MyProblem> {
     if let Err(e) = body.validate() {
         return Err(MyProblem::Validation(e));
     }
 
-    let body = body.into_inner();
+    let email = normalize_email(&body.email);
+    let client = ClientInfo::from_request(&req, state.trust_proxy_headers);
+    throttle_reset_password(&state.db, &state.auth_attempt_limits, &email, &client).await?;
 
     struct UserLookup {
         user_id: Uuid,
         email: String,
         first_name: String,
         last_name: String,
+        locale: Option<String>,
     }
     let user_lookup = query_as!(
         UserLookup,
         "
-            SELECT user__id AS user_id, email, first_name, last_name
+            SELECT user__id AS user_id, email, first_name, last_name, locale
             FROM iam.user
             WHERE email = $1
         ",
-        &body.email,
+        &email,
     )
     .fetch_optional(&state.db)
     .await
     .map_err(MyProblem::from)?;
 
     if let Some(user) = user_lookup {
-        let biscuit_token = create_reset_password_token(&state.biscuit_private_key, user.user_id)
-            .map_err(|e| {
-            error!("Error trying to create reset password token: {e}");
-            MyProblem::InternalServerError
-        })?;
+        let locale = Locale::negotiate(user.locale.as_deref(), &req);
+        // The email is only added to the outbox, so the response time does not tell whether the account exists
+        send_reset_password_email(
+            &state,
+            user.user_id,
+            &user.email,
+            &user.first_name,
+            &user.last_name,
+            locale,
+        )
+        .await?;
 
-        let address = Address::from_str(&user.email).map_err(|e| {
-            error!("Error trying to parse email address: {e}");
-            MyProblem::InternalServerError
-        })?;
-        let recipient = Mailbox::new(
-            Some(format!("{} {}", user.first_name, user.last_name)),
-            address,
-        );
-
-        match state
-            .mailer
-            .send_mail(
-                Mail::ResetPassword {
-                    url: format!(
-                        "{}reset-password?token={}",
-                        state.app_url, &biscuit_token.serialized_biscuit
-                    ),
-                },
-                recipient,
-            )
-            .await
-        {
-            Ok(_) => Ok(NoContent),
-            Err(e) => {
-                error!("Error trying to send email: {e}");
-                Err(MyProblem::InternalServerError)
-            }
-        }
+        Ok(NoContent)
+    } else if state.enumeration_protection {
+        Ok(NoContent)
     } else {
         Err(MyProblem::AuthEmailExpired)
     }
 }
 
+pub(crate) async fn send_reset_password_email(
+    state: &crate::State,
+    user_id: Uuid,
+    email: &str,
+    first_name: &str,
+    last_name: &str,
+    locale: Locale,
+) -> Result<(), MyProblem> {
+    let biscuit_token = create_reset_password_token(&state.biscuit_private_key, user_id)
+        .map_err(|e| {
+        error!("Error trying to create reset password token: {e}");
+        MyProblem::InternalServerError
+    })?;
+
+    let address = Address::from_str(email).map_err(|e| {
+        error!("Error trying to parse email address: {e}");
+        MyProblem::InternalServerError
+    })?;
+    let recipient = Mailbox::new(
+        Some(format!("{first_name} {last_name}")),
+        address,
+    );
+
+    state
+        .mailer
+        .queue_mail(
+            &state.db,
+            Mail::ResetPassword {
+                url: format!(
+                    "{}reset-password?token={}",
+                    state.app_url, &biscuit_token.serialized_biscuit
+                ),
+            },
+            recipient,
+            locale,
+        )
+        .await
+}
+
 #[api_v2_operation(
     summary = "Reset password",
-    description = "Reset the password of a user.",
+    description = "Reset the password of a user. All sessions of the user are revoked.",
     operation_id = "auth.reset_password",
     consumes = "application/json",
     produces = "application/json",
@@ -583,6 +713,9 @@ pub async fn reset_password(
             )
             .await?;
 
+            // Someone who could reset the password might not be the one who opened the existing sessions
+            revoke_user_sessions(&mut tx, user_id, None).await?;
+
             query!(
                 "
                     UPDATE iam.user
@@ -607,7 +740,7 @@ pub async fn reset_password(
 
 #[api_v2_operation(
     summary = "Change password",
-    description = "Change the password of a user.",
+    description = "Change the password of a user. All other sessions of the user are revoked.",
     operation_id = "auth.change_password",
     consumes = "application/json",
     produces = "application/json",
@@ -629,20 +762,211 @@ pub async fn change_password(
         &biscuit,
         Action::AuthChangePassword,
     ) {
+        let password_hash = query_scalar!(
+            "
+                SELECT password
+                FROM iam.user
+                WHERE user__id = $1
+            ",
+            &token.user_id,
+        )
+        .fetch_one(&state.db)
+        .await?;
+
+        if !check_password(&body.current_password, &password_hash, token.user_id)? {
+            return Err(MyProblem::AuthInvalidPassword);
+        }
+
+        let mut tx = state.db.begin().await?;
+
         do_change_password(
-            &state.db,
+            &mut tx,
             state.password_minimum_length,
             &body.new_password,
             token.user_id,
         )
         .await?;
 
+        revoke_user_sessions(&mut tx, token.user_id, Some(token.session_id)).await?;
+
+        tx.commit().await?;
         Ok(NoContent)
     } else {
         Err(MyProblem::Forbidden)
     }
 }
 
+#[api_v2_operation(
+    summary = "Confirm email change",
+    description = "Replace the email address of a user with the one the confirmation link was sent to. The link can only be used once.",
+    operation_id = "auth.confirm_email_change",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn confirm_email_change(
+    state: Data<crate::State>,
+    body: Json<EmailChangePost>,
+) -> Result<NoContent, MyProblem> {
+    if let Err(e) = body.validate() {
+        return Err(MyProblem::Validation(e));
+    }
+
+    let body = body.into_inner();
+
+    let biscuit =
+        Biscuit::from_base64(body.token, state.biscuit_private_key.public()).map_err(|e| {
+            debug!("{e}");
+            MyProblem::AuthEmailExpired
+        })?;
+    let token = authorize_email_change(&biscuit).map_err(|e| {
+        debug!("{e}");
+        MyProblem::AuthEmailExpired
+    })?;
+    let revocation_id = biscuit
+        .revocation_identifiers()
+        .first()
+        .map(|rid| rid.to_owned())
+        .ok_or(MyProblem::AuthEmailExpired)?;
+
+    let mut tx = state.db.begin().await?;
+
+    let consumed = query!(
+        "
+            UPDATE iam.token
+            SET expired_at = statement_timestamp()
+            WHERE revocation_id = $1
+                AND type = 'email_change'
+                AND expired_at > statement_timestamp()
+        ",
+        &revocation_id,
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if consumed == 0 {
+        return Err(MyProblem::AuthEmailExpired);
+    }
+
+    // The change is discarded if the email address was changed by other means in the meantime
+    let user_was_updated = query!(
+        "
+            UPDATE iam.user
+            SET email = $1, email_verified_at = statement_timestamp()
+            WHERE user__id = $2 AND email = $3
+            RETURNING user__id
+        ",
+        &token.new_email,
+        &token.user_id,
+        &token.old_email,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .is_some();
+
+    if user_was_updated {
+        tx.commit().await?;
+        info!("User {} changed its email address", token.user_id);
+        Ok(NoContent)
+    } else {
+        Err(MyProblem::AuthEmailExpired)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Undo email change",
+    description = "Cancel a pending email change or restore the previous email address of a user, using the link sent to that address. All sessions of the user are revoked. The link can only be used once.",
+    operation_id = "auth.undo_email_change",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn undo_email_change(
+    state: Data<crate::State>,
+    body: Json<EmailChangePost>,
+) -> Result<NoContent, MyProblem> {
+    if let Err(e) = body.validate() {
+        return Err(MyProblem::Validation(e));
+    }
+
+    let body = body.into_inner();
+
+    let biscuit =
+        Biscuit::from_base64(body.token, state.biscuit_private_key.public()).map_err(|e| {
+            debug!("{e}");
+            MyProblem::AuthEmailExpired
+        })?;
+    let token = authorize_email_change_undo(&biscuit).map_err(|e| {
+        debug!("{e}");
+        MyProblem::AuthEmailExpired
+    })?;
+    let revocation_id = biscuit
+        .revocation_identifiers()
+        .first()
+        .map(|rid| rid.to_owned())
+        .ok_or(MyProblem::AuthEmailExpired)?;
+
+    let mut tx = state.db.begin().await?;
+
+    let consumed = query!(
+        "
+            UPDATE iam.token
+            SET expired_at = statement_timestamp()
+            WHERE revocation_id = $1
+                AND type = 'email_change_undo'
+                AND expired_at > statement_timestamp()
+        ",
+        &revocation_id,
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if consumed == 0 {
+        return Err(MyProblem::AuthEmailExpired);
+    }
+
+    query!(
+        "
+            UPDATE iam.token
+            SET expired_at = statement_timestamp()
+            WHERE user__id = $1
+                AND type = 'email_change'
+                AND expired_at > statement_timestamp()
+        ",
+        &token.user_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let email_was_restored = query!(
+        "
+            UPDATE iam.user
+            SET email = $1
+            WHERE user__id = $2 AND email = $3
+            RETURNING user__id
+        ",
+        &token.old_email,
+        &token.user_id,
+        &token.new_email,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .is_some();
+
+    // Whoever requested the change knew the password, so they must not stay logged in
+    revoke_user_sessions(&mut tx, token.user_id, None).await?;
+
+    tx.commit().await?;
+
+    if email_was_restored {
+        warn!("User {} restored its previous email address", token.user_id);
+    }
+
+    Ok(NoContent)
+}
+
 async fn do_change_password<'a, A: Acquire<'a, Database = Postgres>>(
     db: A,
     password_minimum_length: u8,
@@ -675,7 +999,36 @@ async fn do_change_password<'a, A: Acquire<'a, Database = Postgres>>(
     }
 }
 
-fn generate_hashed_password(password: &str) -> Result<PasswordHashString, MyProblem> {
+/// Check a password against the stored Argon2 hash of a user
+pub(crate) fn check_password(
+    password: &str,
+    password_hash: &str,
+    user_id: Uuid,
+) -> Result<bool, MyProblem> {
+    let password_hash = PasswordHash::new(password_hash).map_err(|e| {
+        error!("Password hash of user {user_id} is not in the right format: {e}");
+        MyProblem::InternalServerError
+    })?;
+
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &password_hash)
+        .is_ok())
+}
+
+/// Verify a password against a hash that matches no password, so that requests about unknown users take as long as the ones about existing users
+pub(crate) fn check_dummy_password(password: &str) {
+    static DUMMY_PASSWORD_HASH: OnceLock<Option<PasswordHashString>> = OnceLock::new();
+
+    let dummy_password_hash = DUMMY_PASSWORD_HASH
+        .get_or_init(|| generate_hashed_password(&Uuid::new_v4().to_string()).ok());
+
+    if let Some(dummy_password_hash) = dummy_password_hash {
+        let _ = Argon2::default()
+            .verify_password(password.as_bytes(), &dummy_password_hash.password_hash());
+    }
+}
+
+pub(crate) fn generate_hashed_password(password: &str) -> Result<PasswordHashString, MyProblem> {
     let salt =
         argon2::password_hash::SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
     let password_hash = Argon2::default()
@@ -687,4 +1040,80 @@ fn generate_hashed_password(password: &str) -> Result<PasswordHashString, MyProb
         .serialize();
 
     Ok(password_hash)
+}
+
+#[api_v2_operation(
+    summary = "Cancel account deletion",
+    description = "Cancel the pending deletion of a user account, using the link sent by email when the deletion was requested. The link can only be used once and only cancels the last deletion request. The user can log in again afterwards.",
+    operation_id = "auth.cancel_account_deletion",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn cancel_account_deletion(
+    state: Data<crate::State>,
+    body: Json<AccountDeletionCancelPost>,
+) -> Result<NoContent, MyProblem> {
+    if let Err(e) = body.validate() {
+        return Err(MyProblem::Validation(e));
+    }
+
+    let body = body.into_inner();
+
+    let biscuit =
+        Biscuit::from_base64(body.token, state.biscuit_private_key.public()).map_err(|e| {
+            debug!("{e}");
+            MyProblem::AuthEmailExpired
+        })?;
+    let token = authorize_account_deletion_cancel(&biscuit).map_err(|e| {
+        debug!("{e}");
+        MyProblem::AuthEmailExpired
+    })?;
+    let revocation_id = biscuit
+        .revocation_identifiers()
+        .first()
+        .map(|rid| rid.to_owned())
+        .ok_or(MyProblem::AuthEmailExpired)?;
+
+    let mut tx = state.db.begin().await?;
+
+    // The link can only be used once, and not after a later deletion request
+    let consumed = query!(
+        "
+            UPDATE iam.token
+            SET expired_at = statement_timestamp()
+            WHERE revocation_id = $1
+                AND type = 'account_deletion_cancel'
+                AND expired_at > statement_timestamp()
+        ",
+        &revocation_id,
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if consumed == 0 {
+        return Err(MyProblem::AuthEmailExpired);
+    }
+
+    // The account may already have been purged, or the deletion already cancelled
+    let cancelled = query!(
+        "
+            UPDATE iam.user
+            SET deletion_requested_at = NULL
+            WHERE user__id = $1 AND deletion_requested_at IS NOT NULL
+            RETURNING user__id
+        ",
+        &token.user_id,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .is_some();
+
+    if cancelled {
+        tx.commit().await?;
+        Ok(NoContent)
+    } else {
+        Err(MyProblem::AuthEmailExpired)
+    }
 }
\ No newline at end of file
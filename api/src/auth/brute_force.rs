@@ -0,0 +1,260 @@
+use chrono::{DateTime, Duration, Utc};
+use lettre::message::Mailbox;
+use lettre::Address;
+use log::{error, warn};
+use sqlx::{query, query_as, PgPool};
+use std::str::FromStr;
+use strum::AsRefStr;
+
+use crate::utils::client_info::ClientInfo;
+use crate::utils::locale::Locale;
+use crate::utils::mailer::Mail;
+use crate::utils::problems::MyProblem;
+
+/// Limits applied to failed logins and reset password requests
+#[derive(Debug, Clone, Copy)]
+pub struct AuthAttemptLimits {
+    pub max_failed_logins_per_account: i64,
+    pub max_failed_logins_per_ip: i64,
+    pub max_reset_password_emails_per_account: i64,
+    /// Duration during which attempts are counted
+    pub window: Duration,
+    /// Duration of a lockout, counted from the last failed login
+    pub lockout_duration: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+enum AuthAttemptType {
+    Login,
+    ResetPassword,
+}
+
+struct AttemptCount {
+    attempts: i64,
+    first_attempt_at: Option<DateTime<Utc>>,
+    last_attempt_at: Option<DateTime<Utc>>,
+}
+
+fn retry_after(until: DateTime<Utc>) -> u64 {
+    u64::try_from((until - Utc::now()).num_seconds())
+        .unwrap_or_default()
+        .max(1)
+}
+
+async fn insert_attempt(
+    db: &PgPool,
+    limits: &AuthAttemptLimits,
+    attempt_type: AuthAttemptType,
+    email: &str,
+    client: &ClientInfo,
+    succeeded: bool,
+) -> Result<(), MyProblem> {
+    let mut tx = db.begin().await?;
+
+    // Attempts older than the window are never counted again, whatever account or IP address they were made for
+    query!(
+        "
+            DELETE FROM iam.auth_attempt
+            WHERE created_at <= $1
+        ",
+        Utc::now() - limits.window,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        "
+            INSERT INTO iam.auth_attempt (type, email, ip_address, succeeded)
+            VALUES ($1, $2, $3, $4)
+        ",
+        attempt_type.as_ref(),
+        email,
+        client.ip,
+        succeeded,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn count_failed_logins_for_account(
+    db: &PgPool,
+    limits: &AuthAttemptLimits,
+    email: &str,
+) -> Result<AttemptCount, MyProblem> {
+    let count = query_as!(
+        AttemptCount,
+        r#"
+            SELECT count(*) AS "attempts!", min(created_at) AS first_attempt_at, max(created_at) AS last_attempt_at
+            FROM iam.auth_attempt
+            WHERE type = 'login'
+                AND email = $1
+                AND NOT succeeded
+                AND created_at > $2
+                AND created_at > COALESCE((
+                    SELECT max(created_at)
+                    FROM iam.auth_attempt
+                    WHERE type = 'login'
+                        AND email = $1
+                        AND succeeded
+                ), '-infinity')
+        "#,
+        email,
+        Utc::now() - limits.window,
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(count)
+}
+
+/// Fail if too many failed logins were recently made for this email address or from this IP address
+pub(crate) async fn check_login_attempts(
+    db: &PgPool,
+    limits: &AuthAttemptLimits,
+    email: &str,
+    client: &ClientInfo,
+) -> Result<(), MyProblem> {
+    let now = Utc::now();
+
+    let account = count_failed_logins_for_account(db, limits, email).await?;
+    if account.attempts >= limits.max_failed_logins_per_account {
+        if let Some(until) = account.last_attempt_at.map(|t| t + limits.lockout_duration) {
+            if until > now {
+                return Err(MyProblem::AuthTooManyAttempts(retry_after(until)));
+            }
+        }
+    }
+
+    if let Some(ip) = client.ip {
+        let ip_address = query_as!(
+            AttemptCount,
+            r#"
+                SELECT count(*) AS "attempts!", min(created_at) AS first_attempt_at, max(created_at) AS last_attempt_at
+                FROM iam.auth_attempt
+                WHERE type = 'login'
+                    AND ip_address = $1
+                    AND NOT succeeded
+                    AND created_at > $2
+            "#,
+            ip,
+            now - limits.window,
+        )
+        .fetch_one(db)
+        .await?;
+
+        if ip_address.attempts >= limits.max_failed_logins_per_ip {
+            if let Some(until) = ip_address.last_attempt_at.map(|t| t + limits.lockout_duration) {
+                if until > now {
+                    return Err(MyProblem::AuthTooManyAttempts(retry_after(until)));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Record a login attempt and notify the account owner if this failure locks their account
+pub(crate) async fn record_login_attempt(
+    state: &crate::State,
+    email: &str,
+    client: &ClientInfo,
+    succeeded: bool,
+) -> Result<(), MyProblem> {
+    let limits = &state.auth_attempt_limits;
+
+    insert_attempt(&state.db, limits, AuthAttemptType::Login, email, client, succeeded).await?;
+
+    if !succeeded {
+        let account = count_failed_logins_for_account(&state.db, limits, email).await?;
+        if account.attempts == limits.max_failed_logins_per_account {
+            warn!("Too many failed logins for {email}; locking the account");
+            notify_account_locked(state, email).await;
+        }
+    }
+
+    Ok(())
+}
+
+async fn notify_account_locked(state: &crate::State, email: &str) {
+    let user = query!(
+        "
+            SELECT email, first_name, last_name, locale
+            FROM iam.user
+            WHERE email = $1
+        ",
+        email,
+    )
+    .fetch_optional(&state.db)
+    .await;
+
+    let user = match user {
+        Ok(Some(user)) => user,
+        Ok(None) => return,
+        Err(e) => {
+            error!("Could not fetch locked user: {e}");
+            return;
+        }
+    };
+
+    let address = match Address::from_str(&user.email) {
+        Ok(address) => address,
+        Err(e) => {
+            error!("Error trying to parse email address: {e}");
+            return;
+        }
+    };
+    let recipient = Mailbox::new(
+        Some(format!("{} {}", user.first_name, user.last_name)),
+        address,
+    );
+
+    let mail = Mail::AccountLocked {
+        url: format!("{}begin-reset-password", state.app_url),
+        duration: u64::try_from(state.auth_attempt_limits.lockout_duration.num_minutes())
+            .unwrap_or_default()
+            .max(1),
+    };
+    // The attempts may not come from the account owner, so the language of the request is ignored
+    let locale = Locale::from_stored(user.locale.as_deref());
+    if let Err(e) = state.mailer.queue_mail(&state.db, mail, recipient, locale).await {
+        error!("Error trying to queue email: {e}");
+    }
+}
+
+/// Fail if too many reset password emails were recently requested for this email address, otherwise record the request
+pub(crate) async fn throttle_reset_password(
+    db: &PgPool,
+    limits: &AuthAttemptLimits,
+    email: &str,
+    client: &ClientInfo,
+) -> Result<(), MyProblem> {
+    let account = query_as!(
+        AttemptCount,
+        r#"
+            SELECT count(*) AS "attempts!", min(created_at) AS first_attempt_at, max(created_at) AS last_attempt_at
+            FROM iam.auth_attempt
+            WHERE type = 'reset_password'
+                AND email = $1
+                AND created_at > $2
+        "#,
+        email,
+        Utc::now() - limits.window,
+    )
+    .fetch_one(db)
+    .await?;
+
+    if account.attempts >= limits.max_reset_password_emails_per_account {
+        let until = account
+            .first_attempt_at
+            .map(|t| t + limits.window)
+            .unwrap_or_else(Utc::now);
+        return Err(MyProblem::AuthTooManyAttempts(retry_after(until)));
+    }
+
+    insert_attempt(db, limits, AuthAttemptType::ResetPassword, email, client, true).await
+}
@@ -1,9 +1,10 @@
+use std::str::FromStr;
 use std::time::{Duration, SystemTime};
 use biscuit_auth::{builder::Fact, builder_ext::AuthorizerExt, error, macros::*, AuthorizerLimits, Biscuit, KeyPair, PrivateKey};
 use chrono::{DateTime, Utc};
 use log::{error, trace};
 use paperclip::v2::schema::TypedData;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use strum::{AsRefStr, EnumIter, EnumString, VariantNames};
 use uuid::Uuid;
 
@@ -27,6 +28,7 @@ pub struct AuthorizedUserToken {
     pub email: String,
     pub first_name: String,
     pub last_name: String,
+    pub role: Role,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -39,6 +41,29 @@ pub struct AuthorizedResetPasswordToken {
     pub user_id: Uuid,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthorizedMfaPendingToken {
+    pub user_id: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthorizedAccountDeletionCancelToken {
+    pub user_id: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthorizedDataExportDownloadToken {
+    pub user_id: Uuid,
+    pub data_export_id: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthorizedEmailChangeToken {
+    pub user_id: Uuid,
+    pub old_email: String,
+    pub new_email: String,
+}
+
 #[derive(
     Debug,
     Clone,
@@ -84,13 +109,45 @@ impl Serialize for Role {
     }
 }
 
+impl<'de> Deserialize<'de> for Role {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let role = String::deserialize(deserializer)?;
+        Role::from_str(&role).map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Action {
     AuthLogout,
     AuthChangePassword,
+    AuthListSessions,
+    AuthRevokeSession,
+    AuthRevokeOtherSessions,
+    AuthEnrollTotp,
+    AuthConfirmTotp,
+    AuthDisableTotp,
+    AuthStartWebauthnRegistration,
+    AuthFinishWebauthnRegistration,
+    AuthListWebauthnCredentials,
+    AuthDeleteWebauthnCredential,
     UserSettingsChangeProfilePicture,
-    UserSettingsChangeName,
+    UserSettingsGetProfilePicture,
+    UserSettingsDeleteProfilePicture,
+    UserSettingsGetProfile,
+    UserSettingsUpdateProfile,
+    UserSettingsChangeEmail,
+    UserSettingsRequestDataExport,
     UserSettingsDeleteUser,
+    AdminListUsers,
+    AdminGetUser,
+    AdminVerifyUserEmail,
+    AdminSendResetPasswordEmail,
+    AdminDisableUser,
+    AdminEnableUser,
+    AdminDeleteUser,
 }
 
 impl<'a> Action {
@@ -98,21 +155,66 @@ impl<'a> Action {
         match self {
             Action::AuthLogout => "auth:logout",
             Action::AuthChangePassword => "auth:change_password",
+            Action::AuthListSessions => "auth:list_sessions",
+            Action::AuthRevokeSession => "auth:revoke_session",
+            Action::AuthRevokeOtherSessions => "auth:revoke_other_sessions",
+            Action::AuthEnrollTotp => "auth:enroll_totp",
+            Action::AuthConfirmTotp => "auth:confirm_totp",
+            Action::AuthDisableTotp => "auth:disable_totp",
+            Action::AuthStartWebauthnRegistration => "auth:start_webauthn_registration",
+            Action::AuthFinishWebauthnRegistration => "auth:finish_webauthn_registration",
+            Action::AuthListWebauthnCredentials => "auth:list_webauthn_credentials",
+            Action::AuthDeleteWebauthnCredential => "auth:delete_webauthn_credential",
             Action::UserSettingsChangeProfilePicture => "users_settings:change_profile_picture",
-            Action::UserSettingsChangeName => "users_settings:change_name",
+            Action::UserSettingsGetProfilePicture => "users_settings:get_profile_picture",
+            Action::UserSettingsDeleteProfilePicture => "users_settings:delete_profile_picture",
+            Action::UserSettingsGetProfile => "users_settings:get_profile",
+            Action::UserSettingsUpdateProfile => "users_settings:update_profile",
+            Action::UserSettingsChangeEmail => "users_settings:change_email",
+            Action::UserSettingsRequestDataExport => "users_settings:request_data_export",
             Action::UserSettingsDeleteUser => "users_settings:delete_user",
+            Action::AdminListUsers => "admin:list_users",
+            Action::AdminGetUser => "admin:get_user",
+            Action::AdminVerifyUserEmail => "admin:verify_user_email",
+            Action::AdminSendResetPasswordEmail => "admin:send_reset_password_email",
+            Action::AdminDisableUser => "admin:disable_user",
+            Action::AdminEnableUser => "admin:enable_user",
+            Action::AdminDeleteUser => "admin:delete_user",
         }
     }
 
     fn allowed_roles(&self) -> Vec<Role> {
-        let mut roles = vec![Role::User];
+        // Administrators are allowed to perform every action
+        let mut roles = vec![Role::Administrator];
 
         let mut per_action_roles = match self {
-            Self::AuthLogout => vec![],
-            Self::AuthChangePassword => vec![],
-            Self::UserSettingsChangeProfilePicture => vec![],
-            Self::UserSettingsChangeName => vec![],
-            Self::UserSettingsDeleteUser => vec![],
+            Self::AuthLogout => vec![Role::User],
+            Self::AuthChangePassword => vec![Role::User],
+            Self::AuthListSessions => vec![Role::User],
+            Self::AuthRevokeSession => vec![Role::User],
+            Self::AuthRevokeOtherSessions => vec![Role::User],
+            Self::AuthEnrollTotp => vec![Role::User],
+            Self::AuthConfirmTotp => vec![Role::User],
+            Self::AuthDisableTotp => vec![Role::User],
+            Self::AuthStartWebauthnRegistration => vec![Role::User],
+            Self::AuthFinishWebauthnRegistration => vec![Role::User],
+            Self::AuthListWebauthnCredentials => vec![Role::User],
+            Self::AuthDeleteWebauthnCredential => vec![Role::User],
+            Self::UserSettingsChangeProfilePicture => vec![Role::User],
+            Self::UserSettingsGetProfilePicture => vec![Role::User],
+            Self::UserSettingsDeleteProfilePicture => vec![Role::User],
+            Self::UserSettingsGetProfile => vec![Role::User],
+            Self::UserSettingsUpdateProfile => vec![Role::User],
+            Self::UserSettingsChangeEmail => vec![Role::User],
+            Self::UserSettingsRequestDataExport => vec![Role::User],
+            Self::UserSettingsDeleteUser => vec![Role::User],
+            Self::AdminListUsers => vec![],
+            Self::AdminGetUser => vec![],
+            Self::AdminVerifyUserEmail => vec![],
+            Self::AdminSendResetPasswordEmail => vec![],
+            Self::AdminDisableUser => vec![],
+            Self::AdminEnableUser => vec![],
+            Self::AdminDeleteUser => vec![],
         };
 
         roles.append(&mut per_action_roles);
@@ -123,9 +225,31 @@ impl<'a> Action {
         let mut facts = match self {
             Self::AuthLogout => vec![],
             Self::AuthChangePassword => vec![],
+            Self::AuthListSessions => vec![],
+            Self::AuthRevokeSession => vec![],
+            Self::AuthRevokeOtherSessions => vec![],
+            Self::AuthEnrollTotp => vec![],
+            Self::AuthConfirmTotp => vec![],
+            Self::AuthDisableTotp => vec![],
+            Self::AuthStartWebauthnRegistration => vec![],
+            Self::AuthFinishWebauthnRegistration => vec![],
+            Self::AuthListWebauthnCredentials => vec![],
+            Self::AuthDeleteWebauthnCredential => vec![],
             Self::UserSettingsChangeProfilePicture => vec![],
-            Self::UserSettingsChangeName => vec![],
+            Self::UserSettingsGetProfilePicture => vec![],
+            Self::UserSettingsDeleteProfilePicture => vec![],
+            Self::UserSettingsGetProfile => vec![],
+            Self::UserSettingsUpdateProfile => vec![],
+            Self::UserSettingsChangeEmail => vec![],
+            Self::UserSettingsRequestDataExport => vec![],
             Self::UserSettingsDeleteUser => vec![],
+            Self::AdminListUsers => vec![],
+            Self::AdminGetUser => vec![],
+            Self::AdminVerifyUserEmail => vec![],
+            Self::AdminSendResetPasswordEmail => vec![],
+            Self::AdminDisableUser => vec![],
+            Self::AdminEnableUser => vec![],
+            Self::AdminDeleteUser => vec![],
         };
 
         facts.push(fact!("action({action})", action = self.action_name()));
@@ -138,9 +262,10 @@ impl<'a> Action {
     }
 }
 
-const USER_ACCESS_TOKEN_VERSION: i64 = 1;
+const USER_ACCESS_TOKEN_VERSION: i64 = 3;
 const USER_ACCESS_TOKEN_EXPIRATION: Duration = Duration::from_secs(60 * 5); // 5 minutes
 
+#[allow(clippy::too_many_arguments)]
 pub fn create_user_access_token(
     private_key: &PrivateKey,
     token_id: Uuid,
@@ -149,10 +274,13 @@ pub fn create_user_access_token(
     email: &str,
     first_name: &str,
     last_name: &str,
+    role: Role,
+    security_stamp: Uuid,
 ) -> Result<RootToken, biscuit_auth::error::Token> {
     let keypair = KeyPair::from(private_key);
     let created_at = SystemTime::now();
     let expired_at = created_at + USER_ACCESS_TOKEN_EXPIRATION;
+    let role = role.as_ref();
 
     let biscuit = {
         let biscuit = biscuit!(
@@ -166,6 +294,8 @@ pub fn create_user_access_token(
                 email({email});
                 first_name({first_name});
                 last_name({last_name});
+                role({role});
+                security_stamp({security_stamp});
 
                 check if time($t), $t < {expired_at};
             "#,
@@ -301,6 +431,193 @@ pub fn create_reset_password_token(
     })
 }
 
+const EMAIL_CHANGE_TOKEN_VERSION: i64 = 1;
+const EMAIL_CHANGE_TOKEN_EXPIRATION: Duration = Duration::from_secs(60 * 30);
+
+pub fn create_email_change_token(
+    private_key: &PrivateKey,
+    user_id: Uuid,
+    old_email: &str,
+    new_email: &str,
+) -> Result<RootToken, biscuit_auth::error::Token> {
+    create_email_change_token_of_type(
+        private_key,
+        "email_change",
+        EMAIL_CHANGE_TOKEN_EXPIRATION,
+        user_id,
+        old_email,
+        new_email,
+    )
+}
+
+const EMAIL_CHANGE_UNDO_TOKEN_EXPIRATION: Duration = Duration::from_secs(60 * 60 * 24 * 7); // 7 days
+
+/// The undo token outlives the confirmation token so that the previous owner of the account can take it back even after the change was confirmed
+pub fn create_email_change_undo_token(
+    private_key: &PrivateKey,
+    user_id: Uuid,
+    old_email: &str,
+    new_email: &str,
+) -> Result<RootToken, biscuit_auth::error::Token> {
+    create_email_change_token_of_type(
+        private_key,
+        "email_change_undo",
+        EMAIL_CHANGE_UNDO_TOKEN_EXPIRATION,
+        user_id,
+        old_email,
+        new_email,
+    )
+}
+
+fn create_email_change_token_of_type(
+    private_key: &PrivateKey,
+    token_type: &str,
+    expiration: Duration,
+    user_id: Uuid,
+    old_email: &str,
+    new_email: &str,
+) -> Result<RootToken, biscuit_auth::error::Token> {
+    let keypair = KeyPair::from(private_key);
+    let created_at = SystemTime::now();
+    let expired_at = created_at + expiration;
+
+    let biscuit = biscuit!(
+        r#"
+            type({token_type});
+            version({EMAIL_CHANGE_TOKEN_VERSION});
+            user_id({user_id});
+            old_email({old_email});
+            new_email({new_email});
+            created_at({created_at});
+            expired_at({expired_at});
+        "#,
+    )
+    .build(&keypair)?;
+    let serialized_biscuit = biscuit.to_base64()?;
+    let revocation_id = biscuit
+        .revocation_identifiers()
+        .first()
+        .map(|rid| rid.to_owned())
+        .ok_or(biscuit_auth::error::Token::InternalError)?;
+
+    Ok(RootToken {
+        biscuit,
+        serialized_biscuit,
+        revocation_id,
+        expired_at: Some(DateTime::from(expired_at)),
+    })
+}
+
+const ACCOUNT_DELETION_CANCEL_TOKEN_VERSION: i64 = 1;
+
+/// The token stays valid until the account is purged, which happens after `grace_period`
+pub fn create_account_deletion_cancel_token(
+    private_key: &PrivateKey,
+    user_id: Uuid,
+    grace_period: Duration,
+) -> Result<RootToken, biscuit_auth::error::Token> {
+    let keypair = KeyPair::from(private_key);
+    let created_at = SystemTime::now();
+    let expired_at = created_at + grace_period;
+
+    let biscuit = biscuit!(
+        r#"
+            type("account_deletion_cancel");
+            version({ACCOUNT_DELETION_CANCEL_TOKEN_VERSION});
+            user_id({user_id});
+            created_at({created_at});
+            expired_at({expired_at});
+        "#,
+    )
+    .build(&keypair)?;
+    let serialized_biscuit = biscuit.to_base64()?;
+    let revocation_id = biscuit
+        .revocation_identifiers()
+        .first()
+        .map(|rid| rid.to_owned())
+        .ok_or(biscuit_auth::error::Token::InternalError)?;
+
+    Ok(RootToken {
+        biscuit,
+        serialized_biscuit,
+        revocation_id,
+        expired_at: Some(DateTime::from(expired_at)),
+    })
+}
+
+const DATA_EXPORT_DOWNLOAD_TOKEN_VERSION: i64 = 1;
+
+pub fn create_data_export_download_token(
+    private_key: &PrivateKey,
+    user_id: Uuid,
+    data_export_id: Uuid,
+    expired_at: SystemTime,
+) -> Result<RootToken, biscuit_auth::error::Token> {
+    let keypair = KeyPair::from(private_key);
+    let created_at = SystemTime::now();
+
+    let biscuit = biscuit!(
+        r#"
+            type("data_export_download");
+            version({DATA_EXPORT_DOWNLOAD_TOKEN_VERSION});
+            user_id({user_id});
+            data_export_id({data_export_id});
+            created_at({created_at});
+            expired_at({expired_at});
+        "#,
+    )
+    .build(&keypair)?;
+    let serialized_biscuit = biscuit.to_base64()?;
+    let revocation_id = biscuit
+        .revocation_identifiers()
+        .first()
+        .map(|rid| rid.to_owned())
+        .ok_or(biscuit_auth::error::Token::InternalError)?;
+
+    Ok(RootToken {
+        biscuit,
+        serialized_biscuit,
+        revocation_id,
+        expired_at: Some(DateTime::from(expired_at)),
+    })
+}
+
+const MFA_PENDING_TOKEN_VERSION: i64 = 1;
+const MFA_PENDING_TOKEN_EXPIRATION: Duration = Duration::from_secs(60 * 5);
+
+pub fn create_mfa_pending_token(
+    private_key: &PrivateKey,
+    user_id: Uuid,
+) -> Result<RootToken, biscuit_auth::error::Token> {
+    let keypair = KeyPair::from(private_key);
+    let created_at = SystemTime::now();
+    let expired_at = created_at + MFA_PENDING_TOKEN_EXPIRATION;
+
+    let biscuit = biscuit!(
+        r#"
+            type("mfa_pending");
+            version({MFA_PENDING_TOKEN_VERSION});
+            user_id({user_id});
+            created_at({created_at});
+            expired_at({expired_at});
+        "#,
+    )
+    .build(&keypair)?;
+    let serialized_biscuit = biscuit.to_base64()?;
+    let revocation_id = biscuit
+        .revocation_identifiers()
+        .first()
+        .map(|rid| rid.to_owned())
+        .ok_or(biscuit_auth::error::Token::InternalError)?;
+
+    Ok(RootToken {
+        biscuit,
+        serialized_biscuit,
+        revocation_id,
+        expired_at: Some(DateTime::from(expired_at)),
+    })
+}
+
 pub fn authorize_only_user(
     biscuit: &Biscuit,
     action: Action,
@@ -383,12 +700,14 @@ pub fn authorize(
             valid_type($t) <- type($t), valid_types($vt), $vt.contains($t);
             check if valid_type($t);
 
-            supported_version("user_access", 1);
+            supported_version("user_access", 3);
             valid_version($t, $v) <- type($t), version($v), supported_version($t, $v);
             check if valid_version($t, $v);
 
             expired($t) <- expired_at($exp), time($t), $exp < $t;
             deny if expired($t);
+
+            allow if role($r), allowed_role($r);
         "#
     );
 
@@ -396,7 +715,6 @@ pub fn authorize(
     for fact in action.generate_facts() {
         authorizer.add_fact(fact)?;
     }
-    authorizer.add_allow_all();
 
     authorizer.set_limits(AuthorizerLimits {
         max_time: Duration::from_millis(5),
@@ -448,12 +766,19 @@ pub fn authorize(
                 .0
                 .to_owned();
 
+            let raw_role: Vec<(String,)> = authorizer.query(rule!("data($role) <- role($role)"))?;
+            let role = raw_role
+                .first()
+                .and_then(|(str,)| Role::from_str(str).ok())
+                .ok_or(biscuit_auth::error::Token::InternalError)?;
+
             Ok(AuthorizedToken::User(AuthorizedUserToken {
                 session_id,
                 user_id,
                 email,
                 first_name,
                 last_name,
+                role,
             }))
         },
         _ => {
@@ -559,4 +884,220 @@ pub fn authorize_reset_password(
         .ok_or(biscuit_auth::error::Token::InternalError)?;
 
     Ok(AuthorizedResetPasswordToken { user_id })
-}
\ No newline at end of file
+}
+
+pub fn authorize_mfa_pending(
+    biscuit: &Biscuit,
+) -> Result<AuthorizedMfaPendingToken, biscuit_auth::error::Token> {
+    let mut authorizer = authorizer!(
+        r#"
+            supported_version("mfa_pending", 1);
+            valid_version($t, $v) <- type($t), version($v), supported_version($t, $v);
+            check if valid_version($t, $v);
+
+            expired($t) <- expired_at($exp), time($t), $exp < $t;
+            deny if expired($t);
+        "#
+    );
+    authorizer.set_time();
+    authorizer.add_allow_all();
+
+    authorizer.set_limits(AuthorizerLimits {
+        max_time: Duration::from_millis(5),
+        ..Default::default()
+    });
+    authorizer.add_token(biscuit)?;
+    let result = authorizer.authorize();
+    trace!("Authorizer state:\n{}", authorizer.print_world());
+    result?;
+
+    let raw_user_id: Vec<(Vec<u8>,)> = authorizer.query(rule!("data($id) <- user_id($id)"))?;
+    let user_id = raw_user_id
+        .first()
+        .and_then(|(str,)| Uuid::from_slice(str).ok())
+        .ok_or(biscuit_auth::error::Token::InternalError)?;
+
+    Ok(AuthorizedMfaPendingToken { user_id })
+}
+
+pub fn authorize_account_deletion_cancel(
+    biscuit: &Biscuit,
+) -> Result<AuthorizedAccountDeletionCancelToken, biscuit_auth::error::Token> {
+    let mut authorizer = authorizer!(
+        r#"
+            supported_version("account_deletion_cancel", 1);
+            valid_version($t, $v) <- type($t), version($v), supported_version($t, $v);
+            check if valid_version($t, $v);
+
+            expired($t) <- expired_at($exp), time($t), $exp < $t;
+            deny if expired($t);
+        "#
+    );
+    authorizer.set_time();
+    authorizer.add_allow_all();
+
+    authorizer.set_limits(AuthorizerLimits {
+        max_time: Duration::from_millis(5),
+        ..Default::default()
+    });
+    authorizer.add_token(biscuit)?;
+    let result = authorizer.authorize();
+    trace!("Authorizer state:\n{}", authorizer.print_world());
+    result?;
+
+    let raw_user_id: Vec<(Vec<u8>,)> = authorizer.query(rule!("data($id) <- user_id($id)"))?;
+    let user_id = raw_user_id
+        .first()
+        .and_then(|(str,)| Uuid::from_slice(str).ok())
+        .ok_or(biscuit_auth::error::Token::InternalError)?;
+
+    Ok(AuthorizedAccountDeletionCancelToken { user_id })
+}
+
+pub fn authorize_data_export_download(
+    biscuit: &Biscuit,
+) -> Result<AuthorizedDataExportDownloadToken, biscuit_auth::error::Token> {
+    let mut authorizer = authorizer!(
+        r#"
+            supported_version("data_export_download", 1);
+            valid_version($t, $v) <- type($t), version($v), supported_version($t, $v);
+            check if valid_version($t, $v);
+
+            expired($t) <- expired_at($exp), time($t), $exp < $t;
+            deny if expired($t);
+        "#
+    );
+    authorizer.set_time();
+    authorizer.add_allow_all();
+
+    authorizer.set_limits(AuthorizerLimits {
+        max_time: Duration::from_millis(5),
+        ..Default::default()
+    });
+    authorizer.add_token(biscuit)?;
+    let result = authorizer.authorize();
+    trace!("Authorizer state:\n{}", authorizer.print_world());
+    result?;
+
+    let raw_user_id: Vec<(Vec<u8>,)> = authorizer.query(rule!("data($id) <- user_id($id)"))?;
+    let user_id = raw_user_id
+        .first()
+        .and_then(|(str,)| Uuid::from_slice(str).ok())
+        .ok_or(biscuit_auth::error::Token::InternalError)?;
+
+    let raw_data_export_id: Vec<(Vec<u8>,)> =
+        authorizer.query(rule!("data($id) <- data_export_id($id)"))?;
+    let data_export_id = raw_data_export_id
+        .first()
+        .and_then(|(str,)| Uuid::from_slice(str).ok())
+        .ok_or(biscuit_auth::error::Token::InternalError)?;
+
+    Ok(AuthorizedDataExportDownloadToken {
+        user_id,
+        data_export_id,
+    })
+}
+
+pub fn authorize_email_change(
+    biscuit: &Biscuit,
+) -> Result<AuthorizedEmailChangeToken, biscuit_auth::error::Token> {
+    authorize_email_change_token_of_type(biscuit, "email_change")
+}
+
+pub fn authorize_email_change_undo(
+    biscuit: &Biscuit,
+) -> Result<AuthorizedEmailChangeToken, biscuit_auth::error::Token> {
+    authorize_email_change_token_of_type(biscuit, "email_change_undo")
+}
+
+fn authorize_email_change_token_of_type(
+    biscuit: &Biscuit,
+    token_type: &str,
+) -> Result<AuthorizedEmailChangeToken, biscuit_auth::error::Token> {
+    let mut authorizer = authorizer!(
+        r#"
+            supported_version({token_type}, 1);
+            valid_version($t, $v) <- type($t), version($v), supported_version($t, $v);
+            check if valid_version($t, $v);
+
+            expired($t) <- expired_at($exp), time($t), $exp < $t;
+            deny if expired($t);
+        "#
+    );
+    authorizer.set_time();
+    authorizer.add_allow_all();
+
+    authorizer.set_limits(AuthorizerLimits {
+        max_time: Duration::from_millis(5),
+        ..Default::default()
+    });
+    authorizer.add_token(biscuit)?;
+    let result = authorizer.authorize();
+    trace!("Authorizer state:\n{}", authorizer.print_world());
+    result?;
+
+    let raw_user_id: Vec<(Vec<u8>,)> = authorizer.query(rule!("data($id) <- user_id($id)"))?;
+    let user_id = raw_user_id
+        .first()
+        .and_then(|(str,)| Uuid::from_slice(str).ok())
+        .ok_or(biscuit_auth::error::Token::InternalError)?;
+
+    let raw_old_email: Vec<(String,)> =
+        authorizer.query(rule!("data($email) <- old_email($email)"))?;
+    let old_email = raw_old_email
+        .first()
+        .map(|(email,)| email.to_owned())
+        .ok_or(biscuit_auth::error::Token::InternalError)?;
+
+    let raw_new_email: Vec<(String,)> =
+        authorizer.query(rule!("data($email) <- new_email($email)"))?;
+    let new_email = raw_new_email
+        .first()
+        .map(|(email,)| email.to_owned())
+        .ok_or(biscuit_auth::error::Token::InternalError)?;
+
+    Ok(AuthorizedEmailChangeToken {
+        user_id,
+        old_email,
+        new_email,
+    })
+}
+
+/// Get the security stamp of the user at the time an access token was created, or `None` for other types of tokens
+///
+/// Checks of the token are not evaluated, so this must only be called on tokens that are authorized separately.
+pub fn get_security_stamp(biscuit: &Biscuit) -> Result<Option<Uuid>, biscuit_auth::error::Token> {
+    let mut authorizer = biscuit_auth::Authorizer::new();
+
+    authorizer.set_limits(AuthorizerLimits {
+        max_time: Duration::from_millis(5),
+        ..Default::default()
+    });
+    authorizer.add_token(biscuit)?;
+
+    let raw_security_stamp: Vec<(Vec<u8>,)> =
+        authorizer.query(rule!("data($stamp) <- security_stamp($stamp)"))?;
+
+    Ok(raw_security_stamp
+        .first()
+        .and_then(|(str,)| Uuid::from_slice(str).ok()))
+}
+
+/// Get the user ID of any token without evaluating its checks; this must only be used to attribute requests, never to authorize them
+pub fn get_user_id(biscuit: &Biscuit) -> Result<Uuid, biscuit_auth::error::Token> {
+    let mut authorizer = biscuit_auth::Authorizer::new();
+
+    authorizer.set_limits(AuthorizerLimits {
+        max_time: Duration::from_millis(5),
+        ..Default::default()
+    });
+    authorizer.add_token(biscuit)?;
+
+    let raw_user_id: Vec<(Vec<u8>,)> = authorizer.query(rule!("data($id) <- user_id($id)"))?;
+    let user_id = raw_user_id
+        .first()
+        .and_then(|(str,)| Uuid::from_slice(str).ok())
+        .ok_or(biscuit_auth::error::Token::InternalError)?;
+
+    Ok(user_id)
+}
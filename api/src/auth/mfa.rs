@@ -0,0 +1,568 @@
+use actix_web::web::ReqData;
+use actix_web::HttpRequest;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use biscuit_auth::Biscuit;
+use log::{debug, error};
+use paperclip::actix::web::{Data, Json};
+use paperclip::actix::{api_v2_operation, Apiv2Schema, CreatedJson, NoContent};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, query_scalar, Acquire, Postgres};
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::auth::auth::{
+    check_password, do_login, generate_hashed_password, lookup_user, LoginResponse,
+};
+use crate::auth::brute_force::{check_login_attempts, record_login_attempt};
+use crate::auth::iam::{authorize_mfa_pending, authorize_only_user, create_mfa_pending_token, Action};
+use crate::utils::client_info::ClientInfo;
+use crate::utils::openapi::OaBiscuitUserAccess;
+use crate::utils::problems::MyProblem;
+use crate::APP_TITLE;
+
+const TOTP_DIGITS: usize = 6;
+const TOTP_SKEW: u8 = 1;
+const TOTP_STEP: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8; 32] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+#[derive(Debug, Serialize, Apiv2Schema)]
+pub struct TotpEnrollment {
+    /// Base32-encoded secret, for authenticator apps that cannot scan QR codes
+    secret: String,
+    /// `otpauth://` URI to display as a QR code
+    provisioning_uri: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
+pub struct TotpConfirmationPost {
+    #[validate(non_control_character, length(min = 6, max = 6))]
+    code: String,
+}
+
+#[derive(Debug, Serialize, Apiv2Schema)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
+pub struct TotpDisablePost {
+    #[validate(non_control_character, length(min = 1, max = 100))]
+    password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
+pub struct MfaVerifyPost {
+    #[validate(non_control_character, length(min = 1, max = 1000))]
+    mfa_token: String,
+    #[validate(non_control_character, length(min = 6, max = 6))]
+    code: Option<String>,
+    #[validate(non_control_character, length(min = 1, max = 50))]
+    recovery_code: Option<String>,
+}
+
+fn build_totp(secret: Vec<u8>, email: &str) -> Result<TOTP, MyProblem> {
+    TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        TOTP_SKEW,
+        TOTP_STEP,
+        secret,
+        Some(APP_TITLE.to_owned()),
+        email.to_owned(),
+    )
+    .map_err(|e| {
+        error!("Could not build TOTP: {e}");
+        MyProblem::InternalServerError
+    })
+}
+
+fn check_totp_code(totp: &TOTP, code: &str) -> Result<bool, MyProblem> {
+    totp.check_current(code).map_err(|e| {
+        error!("Could not check TOTP code: {e}");
+        MyProblem::InternalServerError
+    })
+}
+
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; RECOVERY_CODE_LENGTH];
+    OsRng.fill_bytes(&mut bytes);
+    let code: String = bytes
+        .iter()
+        .map(|b| char::from(RECOVERY_CODE_ALPHABET[usize::from(*b) % RECOVERY_CODE_ALPHABET.len()]))
+        .collect();
+    format!("{}-{}", &code[..RECOVERY_CODE_LENGTH / 2], &code[RECOVERY_CODE_LENGTH / 2..])
+}
+
+/// Recovery codes are hashed and checked without their separator, so that they can be typed without it or with spaces
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Create a short-lived token that must be exchanged, along with a second factor, to finish logging in
+pub(crate) async fn start_mfa_challenge(
+    state: &crate::State,
+    user_id: Uuid,
+) -> Result<String, MyProblem> {
+    let mfa_token = create_mfa_pending_token(&state.biscuit_private_key, user_id).map_err(|e| {
+        error!("Could not create a Biscuit (MFA pending token): {e}");
+        MyProblem::InternalServerError
+    })?;
+
+    query!(
+        "
+            INSERT INTO iam.token (type, revocation_id, expired_at, user__id)
+            VALUES ('mfa_pending', $1, $2, $3)
+        ",
+        &mfa_token.revocation_id,
+        mfa_token.expired_at,
+        &user_id,
+    )
+    .execute(&state.db)
+    .await?;
+
+    Ok(mfa_token.serialized_biscuit)
+}
+
+#[api_v2_operation(
+    summary = "Enroll TOTP",
+    description = "Generate a new TOTP secret for the current user. Two-factor authentication is only enabled once the secret is confirmed using the `auth.confirm_totp` operation.",
+    operation_id = "auth.enroll_totp",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn enroll_totp(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+) -> Result<CreatedJson<TotpEnrollment>, MyProblem> {
+    if let Ok(token) = authorize_only_user(&biscuit, Action::AuthEnrollTotp) {
+        let secret = Secret::generate_secret().to_bytes().map_err(|e| {
+            error!("Could not generate TOTP secret: {e}");
+            MyProblem::InternalServerError
+        })?;
+        let totp = build_totp(secret.clone(), &token.email)?;
+
+        let updated = query!(
+            "
+                UPDATE iam.user
+                SET totp_secret = $1
+                WHERE user__id = $2
+                    AND totp_enabled_at IS NULL
+            ",
+            &secret,
+            &token.user_id,
+        )
+        .execute(&state.db)
+        .await?
+        .rows_affected();
+
+        if updated == 0 {
+            return Err(MyProblem::AuthTotpAlreadyEnabled);
+        }
+
+        Ok(CreatedJson(TotpEnrollment {
+            secret: totp.get_secret_base32(),
+            provisioning_uri: totp.get_url(),
+        }))
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Confirm TOTP",
+    description = "Enable two-factor authentication by providing a code generated from the enrolled secret. Returns single-use recovery codes that are shown only once.",
+    operation_id = "auth.confirm_totp",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn confirm_totp(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+    body: Json<TotpConfirmationPost>,
+) -> Result<CreatedJson<RecoveryCodes>, MyProblem> {
+    if let Err(e) = body.validate() {
+        return Err(MyProblem::Validation(e));
+    }
+
+    if let Ok(token) = authorize_only_user(&biscuit, Action::AuthConfirmTotp) {
+        let mut tx = state.db.begin().await?;
+
+        let secret = query_scalar!(
+            r#"
+                SELECT totp_secret AS "totp_secret!"
+                FROM iam.user
+                WHERE user__id = $1
+                    AND totp_secret IS NOT NULL
+                    AND totp_enabled_at IS NULL
+                FOR UPDATE
+            "#,
+            &token.user_id,
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(MyProblem::NotFound)?;
+
+        let totp = build_totp(secret, &token.email)?;
+        if !check_totp_code(&totp, &body.code)? {
+            return Err(MyProblem::AuthInvalidMfaCode);
+        }
+
+        query!(
+            "
+                UPDATE iam.user
+                SET totp_enabled_at = statement_timestamp()
+                WHERE user__id = $1
+            ",
+            &token.user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let recovery_codes = replace_recovery_codes(&mut tx, token.user_id).await?;
+
+        tx.commit().await?;
+        Ok(CreatedJson(RecoveryCodes { recovery_codes }))
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Disable TOTP",
+    description = "Disable two-factor authentication for the current user and delete their recovery codes.",
+    operation_id = "auth.disable_totp",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn disable_totp(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+    body: Json<TotpDisablePost>,
+) -> Result<NoContent, MyProblem> {
+    if let Err(e) = body.validate() {
+        return Err(MyProblem::Validation(e));
+    }
+
+    if let Ok(token) = authorize_only_user(&biscuit, Action::AuthDisableTotp) {
+        let password_hash = query_scalar!(
+            "
+                SELECT password
+                FROM iam.user
+                WHERE user__id = $1
+            ",
+            &token.user_id,
+        )
+        .fetch_one(&state.db)
+        .await?;
+
+        if !check_password(&body.password, &password_hash, token.user_id)? {
+            return Err(MyProblem::AuthInvalidPassword);
+        }
+
+        let mut tx = state.db.begin().await?;
+
+        query!(
+            "
+                UPDATE iam.user
+                SET totp_secret = NULL, totp_enabled_at = NULL
+                WHERE user__id = $1
+            ",
+            &token.user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        query!(
+            "
+                DELETE FROM iam.recovery_code
+                WHERE user__id = $1
+            ",
+            &token.user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(NoContent)
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Verify second factor",
+    description = "Finish logging in by exchanging the `mfa_token` obtained from the `auth.login` operation and either a TOTP code or a recovery code. The `mfa_token` can only be used once.",
+    operation_id = "auth.mfa_verify",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn verify(
+    state: Data<crate::State>,
+    req: HttpRequest,
+    body: Json<MfaVerifyPost>,
+) -> Result<CreatedJson<LoginResponse>, MyProblem> {
+    if let Err(e) = body.validate() {
+        return Err(MyProblem::Validation(e));
+    }
+
+    let body = body.into_inner();
+
+    let biscuit =
+        Biscuit::from_base64(&body.mfa_token, state.biscuit_private_key.public()).map_err(|e| {
+            debug!("{e}");
+            MyProblem::AuthInvalidMfaCode
+        })?;
+    let token = authorize_mfa_pending(&biscuit).map_err(|e| {
+        debug!("{e}");
+        MyProblem::AuthInvalidMfaCode
+    })?;
+    let revocation_id = biscuit
+        .revocation_identifiers()
+        .first()
+        .map(|rid| rid.to_owned())
+        .ok_or(MyProblem::AuthInvalidMfaCode)?;
+
+    let client = ClientInfo::from_request(&req, state.trust_proxy_headers);
+    let user = lookup_user(&state.db, token.user_id).await?;
+    let email = user.email.clone();
+    check_login_attempts(&state.db, &state.auth_attempt_limits, &email, &client).await?;
+
+    let mut tx = state.db.begin().await?;
+
+    // MFA pending tokens are single-use so that codes cannot be brute-forced using a single password login
+    let consumed = query!(
+        "
+            UPDATE iam.token
+            SET expired_at = statement_timestamp()
+            WHERE revocation_id = $1
+                AND type = 'mfa_pending'
+                AND expired_at > statement_timestamp()
+        ",
+        &revocation_id,
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if consumed == 0 {
+        return Err(MyProblem::AuthInvalidMfaCode);
+    }
+
+    let valid = match (body.code.as_deref(), body.recovery_code.as_deref()) {
+        (Some(code), _) => {
+            let user = query!(
+                "
+                    SELECT email, totp_secret
+                    FROM iam.user
+                    WHERE user__id = $1
+                        AND totp_enabled_at IS NOT NULL
+                ",
+                &token.user_id,
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+
+            match user.and_then(|u| u.totp_secret.map(|secret| (secret, u.email))) {
+                Some((secret, email)) => check_totp_code(&build_totp(secret, &email)?, code)?,
+                None => false,
+            }
+        }
+        (None, Some(recovery_code)) => {
+            use_recovery_code(&mut tx, token.user_id, recovery_code).await?
+        }
+        (None, None) => false,
+    };
+
+    if !valid {
+        // Commit anyway so that the MFA pending token is consumed
+        tx.commit().await?;
+        record_login_attempt(&state, &email, &client, false).await?;
+        return Err(MyProblem::AuthInvalidMfaCode);
+    }
+
+    let res = do_login(&mut tx, &state.biscuit_private_key, user, None, &client).await?;
+
+    tx.commit().await?;
+    record_login_attempt(&state, &email, &client, true).await?;
+    Ok(res)
+}
+
+struct RecoveryCodeLookup {
+    recovery_code_id: Uuid,
+    code_hash: String,
+}
+
+async fn use_recovery_code<'a, A: Acquire<'a, Database = Postgres>>(
+    db: A,
+    user_id: Uuid,
+    recovery_code: &str,
+) -> Result<bool, MyProblem> {
+    let mut db = db.acquire().await?;
+    let recovery_code = normalize_recovery_code(recovery_code);
+
+    let codes = query_as!(
+        RecoveryCodeLookup,
+        "
+            SELECT recovery_code__id AS recovery_code_id, code_hash
+            FROM iam.recovery_code
+            WHERE user__id = $1
+                AND used_at IS NULL
+            FOR UPDATE
+        ",
+        &user_id,
+    )
+    .fetch_all(&mut *db)
+    .await?;
+
+    for code in codes {
+        if check_password(&recovery_code, &code.code_hash, user_id)? {
+            query!(
+                "
+                    UPDATE iam.recovery_code
+                    SET used_at = statement_timestamp()
+                    WHERE recovery_code__id = $1
+                ",
+                &code.recovery_code_id,
+            )
+            .execute(&mut *db)
+            .await?;
+
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+async fn replace_recovery_codes<'a, A: Acquire<'a, Database = Postgres>>(
+    db: A,
+    user_id: Uuid,
+) -> Result<Vec<String>, MyProblem> {
+    let mut db = db.acquire().await?;
+
+    query!(
+        "
+            DELETE FROM iam.recovery_code
+            WHERE user__id = $1
+        ",
+        &user_id,
+    )
+    .execute(&mut *db)
+    .await?;
+
+    let mut recovery_codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let recovery_code = generate_recovery_code();
+        let code_hash = generate_hashed_password(&normalize_recovery_code(&recovery_code))?;
+
+        query!(
+            "
+                INSERT INTO iam.recovery_code (user__id, code_hash)
+                VALUES ($1, $2)
+            ",
+            &user_id,
+            code_hash.as_str(),
+        )
+        .execute(&mut *db)
+        .await?;
+
+        recovery_codes.push(recovery_code);
+    }
+
+    Ok(recovery_codes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use actix_web::test::TestRequest;
+    use serde_json::{from_value, json};
+    use sqlx::PgPool;
+    use totp_rs::Secret;
+
+    use super::*;
+    use crate::auth::auth::login;
+    use crate::utils::testing;
+
+    /// Log in with the right password, which starts a new MFA challenge
+    async fn password_login(state: &crate::State) -> Result<String, MyProblem> {
+        let body = from_value(json!({ "email": "user@example.com", "password": testing::PASSWORD }))
+            .unwrap();
+
+        match login(Data::new(state.clone()), TestRequest::default().to_http_request(), Json(body)).await {
+            Err(MyProblem::AuthMfaRequired(mfa_token)) => Ok(mfa_token),
+            Err(problem) => Err(problem),
+            Ok(_) => panic!("Logged in without a second factor"),
+        }
+    }
+
+    async fn verify_code(state: &crate::State, mfa_token: String, code: &str) -> MyProblem {
+        let body = MfaVerifyPost {
+            mfa_token,
+            code: Some(code.to_owned()),
+            recovery_code: None,
+        };
+
+        verify(Data::new(state.clone()), TestRequest::default().to_http_request(), Json(body))
+            .await
+            .err()
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn wrong_codes_lock_the_account(db: PgPool) {
+        let state = testing::state(db.clone());
+        let user_id = testing::create_user(&db, "user@example.com").await;
+        let secret = Secret::generate_secret().to_bytes().unwrap();
+        query!(
+            "
+                UPDATE iam.user
+                SET totp_secret = $2, totp_enabled_at = statement_timestamp()
+                WHERE user__id = $1
+            ",
+            &user_id,
+            &secret,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let totp = build_totp(secret, "user@example.com").unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let valid_codes = [now - TOTP_STEP, now, now + TOTP_STEP].map(|time| totp.generate(time));
+        let wrong_code = ["000000", "111111", "222222", "333333"]
+            .into_iter()
+            .find(|code| !valid_codes.iter().any(|valid| valid == code))
+            .unwrap();
+
+        // Each guess uses a new MFA challenge, as an attacker knowing the password would do
+        for _ in 0..state.auth_attempt_limits.max_failed_logins_per_account {
+            let mfa_token = password_login(&state).await.unwrap();
+            let problem = verify_code(&state, mfa_token, wrong_code).await;
+            assert!(matches!(problem, MyProblem::AuthInvalidMfaCode));
+        }
+
+        // Neither the password nor the right code are accepted until the lockout ends
+        assert!(matches!(
+            password_login(&state).await,
+            Err(MyProblem::AuthTooManyAttempts(_))
+        ));
+        let mfa_token = start_mfa_challenge(&state, user_id).await.unwrap();
+        let problem = verify_code(&state, mfa_token, &totp.generate_current().unwrap()).await;
+        assert!(matches!(problem, MyProblem::AuthTooManyAttempts(_)));
+    }
+}
\ No newline at end of file
@@ -6,11 +6,14 @@ use biscuit_auth::{Biscuit, PrivateKey};
 use futures_util::future::{ok, ready, Ready};
 use futures_util::Future;
 use log::{debug, error, trace};
-use sqlx::{query_scalar, PgPool};
+use sqlx::{query, PgPool};
 use std::pin::Pin;
 use std::rc::Rc;
 use std::task::{Context, Poll};
 
+use crate::auth::iam::get_security_stamp;
+use crate::auth::sessions::detect_refresh_token_reuse;
+use crate::utils::client_info::ClientInfo;
 use crate::utils::problems::MyProblem;
 
 #[derive(Debug, Clone)]
@@ -89,21 +92,39 @@ where
                                 let pool: &'static PgPool = Box::leak(pool);
                                 let srv = Rc::clone(&self.service);
                                 Box::pin(async move {
-                                    let biscuit_token_id = query_scalar!(
-                                        "
-                                            SELECT token__id AS token_id
-                                            FROM iam.token
-                                            WHERE revocation_id = $1
-                                                AND (expired_at IS NULL OR expired_at > statement_timestamp())
+                                    let biscuit_token = query!(
+                                        r#"
+                                            SELECT t.token__id AS token_id, u.security_stamp AS "security_stamp?"
+                                            FROM iam.token AS t
+                                            LEFT JOIN iam.user AS u ON u.user__id = t.user__id
+                                            WHERE t.revocation_id = $1
+                                                AND (t.expired_at IS NULL OR t.expired_at > statement_timestamp())
                                             LIMIT 1
-                                        ",
+                                        "#,
                                         &revocation_id
                                     )
                                     .fetch_optional(pool)
                                     .await;
 
-                                    match biscuit_token_id {
-                                        Ok(Some(token_id)) => {
+                                    match biscuit_token {
+                                        Ok(Some(token)) => {
+                                            let token_id = token.token_id;
+
+                                            // Identity claims of access tokens are only trusted while the account has not changed since they were created
+                                            match get_security_stamp(&biscuit) {
+                                                Ok(Some(security_stamp)) if token.security_stamp != Some(security_stamp) => {
+                                                    let e = MyProblem::AuthStaleBiscuit;
+                                                    debug!("{e} (token ID = {token_id})");
+                                                    return Ok(req.error_response(e));
+                                                }
+                                                Ok(_) => {}
+                                                Err(err) => {
+                                                    let e = MyProblem::AuthBiscuitLookupError;
+                                                    debug!("{e}: {err}");
+                                                    return Ok(req.error_response(e));
+                                                }
+                                            }
+
                                             {
                                                 debug!(
                                                     "Auth with Biscuit succeeded (token ID = {})",
@@ -115,6 +136,15 @@ where
                                             srv.call(req).await
                                         }
                                         Ok(None) => {
+                                            let trust_proxy_headers = req
+                                                .app_data::<actix_web::web::Data<crate::State>>()
+                                                .map(|state| state.trust_proxy_headers)
+                                                .unwrap_or(false);
+                                            let client = ClientInfo::from_request(req.request(), trust_proxy_headers);
+                                            if let Err(err) = detect_refresh_token_reuse(pool, &revocation_id, &client).await {
+                                                error!("Could not check if a refresh token was reused: {err}");
+                                            }
+
                                             let e = MyProblem::AuthInvalidBiscuit;
                                             debug!("{e} (root token was not found in database or was expired)");
                                             Ok(req.error_response(e))
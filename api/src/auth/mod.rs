@@ -4,4 +4,14 @@ pub mod registration;
 
 pub mod auth;
 
-pub mod middleware_biscuit;
\ No newline at end of file
+pub mod brute_force;
+
+pub mod middleware_biscuit;
+
+pub mod mfa;
+
+pub mod security_events;
+
+pub mod sessions;
+
+pub mod webauthn;
\ No newline at end of file
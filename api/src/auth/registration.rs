@@ -1,9 +1,10 @@
 use argon2::password_hash::rand_core::OsRng;
 use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHasher};
+use actix_web::HttpRequest;
 use lettre::message::Mailbox;
 use lettre::Address;
-use log::{error, warn};
+use log::error;
 use paperclip::actix::web::{Data, Json};
 use paperclip::actix::{api_v2_operation, Apiv2Schema, CreatedJson};
 use serde::{Deserialize, Serialize};
@@ -12,8 +13,11 @@ use std::str::FromStr;
 use uuid::Uuid;
 use validator::Validate;
 
+use crate::utils::email::normalize_email;
+use crate::utils::locale::Locale;
 use crate::utils::problems::MyProblem;
 use crate::utils::mailer::Mail;
+use crate::auth::auth::generate_hashed_password;
 use crate::auth::iam::create_email_verification_token;
 
 #[derive(Debug, Serialize, Apiv2Schema)]
@@ -43,6 +47,7 @@ pub struct RegistrationPost {
 )]
 pub async fn register(
     state: Data<crate::State>,
+    req: HttpRequest,
     body: Json<RegistrationPost>,
 ) -> Result<CreatedJson<Registration>, MyProblem> {
 
@@ -50,13 +55,48 @@ pub async fn register(
         return Err(MyProblem::Validation(e));
     }
 
-    let recipient_address = Address::from_str(&body.email).map_err(|e| {
+    let email = normalize_email(&body.email);
+
+    let recipient_address = Address::from_str(&email).map_err(|e| {
         // Should not happen because we checked (using a validator) that body.email is a well structured email address
         error!("Error trying to parse email address: {e}");
         MyProblem::InternalServerError
     })?;
 
     if body.password.len() >= usize::from(state.password_minimum_length) {
+        if state.enumeration_protection {
+            let existing_user = query!(
+                "
+                    SELECT email, first_name, last_name, locale
+                    FROM iam.user
+                    WHERE email = $1
+                ",
+                &email,
+            )
+            .fetch_optional(&state.db)
+            .await?;
+
+            if let Some(existing_user) = existing_user {
+                // Hash the password anyway so that the response time is similar to an actual registration
+                generate_hashed_password(&body.password)?;
+
+                // The email is only added to the outbox, so the response time stays the same
+                notify_registration_attempt(
+                    &state,
+                    &existing_user.email,
+                    &existing_user.first_name,
+                    &existing_user.last_name,
+                    Locale::from_stored(existing_user.locale.as_deref()),
+                )
+                .await?;
+
+                // Respond as if the account had been created so that existing accounts cannot be discovered
+                return Ok(CreatedJson(Registration {
+                    user_id: Uuid::new_v4(),
+                }));
+            }
+        }
+
         let mut tx = state.db.begin().await?;
 
         let user_id = Uuid::new_v4();
@@ -74,7 +114,7 @@ pub async fn register(
                 VALUES ($1, $2, $3, $4, $5)
             ",
             &user_id,
-            &body.email,
+            &email,
             password_hash.as_str(),
             &body.first_name,
             &body.last_name,
@@ -93,7 +133,8 @@ pub async fn register(
         );
         state
             .mailer
-            .send_mail(
+            .queue_mail(
+                &mut *tx,
                 Mail::VerifyUserEmail {
                     url: format!(
                         "{}verify-email?token={}",
@@ -101,12 +142,9 @@ pub async fn register(
                     ),
                 },
                 recipient,
+                Locale::negotiate(None, &req),
             )
-            .await
-            .map_err(|e| {
-                warn!("Could not send verification email: {e}");
-                e
-            })?;
+            .await?;
 
         tx.commit().await?;
 
@@ -119,3 +157,30 @@ pub async fn register(
         ))
     }
 }
+
+/// Let the owner of an account know that someone tried to register again with their email address
+async fn notify_registration_attempt(
+    state: &crate::State,
+    email: &str,
+    first_name: &str,
+    last_name: &str,
+    locale: Locale,
+) -> Result<(), MyProblem> {
+    let address = Address::from_str(email).map_err(|e| {
+        error!("Error trying to parse email address: {e}");
+        MyProblem::InternalServerError
+    })?;
+    let recipient = Mailbox::new(Some(format!("{first_name} {last_name}")), address);
+
+    state
+        .mailer
+        .queue_mail(
+            &state.db,
+            Mail::RegistrationAttempt {
+                url: format!("{}begin-reset-password", state.app_url),
+            },
+            recipient,
+            locale,
+        )
+        .await
+}
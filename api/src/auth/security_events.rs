@@ -0,0 +1,41 @@
+use serde_json::Value;
+use sqlx::{query, Acquire, Postgres};
+use strum::AsRefStr;
+use uuid::Uuid;
+
+use crate::utils::client_info::ClientInfo;
+use crate::utils::problems::MyProblem;
+
+/// Security-relevant events that are kept in the `iam.security_event` table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum SecurityEvent {
+    RefreshTokenReuse,
+}
+
+pub(crate) async fn record_security_event<'a, A: Acquire<'a, Database = Postgres>>(
+    db: A,
+    event: SecurityEvent,
+    user_id: Option<Uuid>,
+    session_id: Option<Uuid>,
+    client: &ClientInfo,
+    details: Option<Value>,
+) -> Result<(), MyProblem> {
+    let mut db = db.acquire().await?;
+
+    query!(
+        "
+            INSERT INTO iam.security_event (type, user__id, session_id, ip_address, details)
+            VALUES ($1, $2, $3, $4, $5)
+        ",
+        event.as_ref(),
+        user_id,
+        session_id,
+        client.ip,
+        details,
+    )
+    .execute(&mut *db)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,244 @@
+use actix_web::web::ReqData;
+use biscuit_auth::Biscuit;
+use chrono::{DateTime, Utc};
+use log::warn;
+use paperclip::actix::web::{Data, Json, Path};
+use paperclip::actix::{api_v2_operation, Apiv2Schema, NoContent};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::types::ipnetwork::IpNetwork;
+use sqlx::{query, query_as, Acquire, PgPool, Postgres};
+use uuid::Uuid;
+
+use crate::auth::iam::{authorize_only_user, Action};
+use crate::auth::security_events::{record_security_event, SecurityEvent};
+use crate::utils::client_info::ClientInfo;
+use crate::utils::openapi::OaBiscuitUserAccess;
+use crate::utils::problems::MyProblem;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SessionRow {
+    session_id: Uuid,
+    created_at: DateTime<Utc>,
+    last_refreshed_at: Option<DateTime<Utc>>,
+    user_agent: Option<String>,
+    ip_address: Option<IpNetwork>,
+}
+
+#[derive(Debug, Serialize, Apiv2Schema)]
+pub struct Session {
+    session_id: Uuid,
+    created_at: DateTime<Utc>,
+    last_refreshed_at: Option<DateTime<Utc>>,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+    current: bool,
+}
+
+#[api_v2_operation(
+    summary = "List sessions",
+    description = "List the active sessions of the current user.",
+    operation_id = "auth.list_sessions",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn list_sessions(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+) -> Result<Json<Vec<Session>>, MyProblem> {
+    if let Ok(token) = authorize_only_user(&biscuit, Action::AuthListSessions) {
+        let sessions = query_as!(
+            SessionRow,
+            "
+                SELECT s.session__id AS session_id, s.created_at, s.last_refreshed_at, s.user_agent, s.ip_address
+                FROM iam.session AS s
+                WHERE s.user__id = $1
+                    AND EXISTS (
+                        SELECT 1
+                        FROM iam.token AS t
+                        WHERE t.session_id = s.session__id
+                            AND t.type = 'refresh'
+                            AND t.expired_at > statement_timestamp()
+                    )
+                ORDER BY COALESCE(s.last_refreshed_at, s.created_at) DESC
+            ",
+            &token.user_id,
+        )
+        .fetch_all(&state.db)
+        .await?
+        .into_iter()
+        .map(|row| Session {
+            current: row.session_id == token.session_id,
+            session_id: row.session_id,
+            created_at: row.created_at,
+            last_refreshed_at: row.last_refreshed_at,
+            user_agent: row.user_agent,
+            ip_address: row.ip_address.map(|ip| ip.ip().to_string()),
+        })
+        .collect();
+
+        Ok(Json(sessions))
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Revoke a session",
+    description = "Revoke all tokens associated to one of the sessions of the current user.",
+    operation_id = "auth.revoke_session",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn revoke_session(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+    session_id: Path<Uuid>,
+) -> Result<NoContent, MyProblem> {
+    if let Ok(token) = authorize_only_user(&biscuit, Action::AuthRevokeSession) {
+        let revoked = query!(
+            "
+                UPDATE iam.token
+                SET expired_at = statement_timestamp()
+                WHERE user__id = $1
+                    AND expired_at > statement_timestamp()
+                    AND session_id = $2
+                    AND type IN ('user_access', 'refresh')
+            ",
+            &token.user_id,
+            &session_id.into_inner(),
+        )
+        .execute(&state.db)
+        .await?
+        .rows_affected();
+
+        if revoked > 0 {
+            Ok(NoContent)
+        } else {
+            Err(MyProblem::NotFound)
+        }
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Revoke other sessions",
+    description = "Revoke all sessions of the current user except the current one.",
+    operation_id = "auth.revoke_other_sessions",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn revoke_other_sessions(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+) -> Result<NoContent, MyProblem> {
+    if let Ok(token) = authorize_only_user(&biscuit, Action::AuthRevokeOtherSessions) {
+        revoke_user_sessions(&state.db, token.user_id, Some(token.session_id)).await?;
+
+        Ok(NoContent)
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+/// Revoke all tokens of all sessions of a user, except the ones of `except_session_id`
+pub(crate) async fn revoke_user_sessions<'a, A: Acquire<'a, Database = Postgres>>(
+    db: A,
+    user_id: Uuid,
+    except_session_id: Option<Uuid>,
+) -> Result<u64, MyProblem> {
+    let mut db = db.acquire().await?;
+
+    let revoked = query!(
+        "
+            UPDATE iam.token
+            SET expired_at = statement_timestamp()
+            WHERE user__id = $1
+                AND expired_at > statement_timestamp()
+                AND type IN ('user_access', 'refresh')
+                AND ($2::uuid IS NULL OR session_id <> $2)
+        ",
+        &user_id,
+        except_session_id,
+    )
+    .execute(&mut *db)
+    .await?
+    .rows_affected();
+
+    Ok(revoked)
+}
+
+/// Check whether a refresh token that was rejected had already been rotated, and react to it if so
+///
+/// As recommended by the OAuth 2.0 Security Best Current Practice, a replayed refresh token means that either the legitimate client or an attacker holds a stolen token, so the whole session is revoked.
+pub(crate) async fn detect_refresh_token_reuse(
+    db: &PgPool,
+    revocation_id: &[u8],
+    client: &ClientInfo,
+) -> Result<bool, MyProblem> {
+    let rotated_token = query!(
+        r#"
+            SELECT token__id AS token_id, session_id AS "session_id!", user__id AS "user_id!"
+            FROM iam.token
+            WHERE revocation_id = $1
+                AND type = 'refresh'
+                AND rotated_at IS NOT NULL
+            LIMIT 1
+        "#,
+        revocation_id,
+    )
+    .fetch_optional(db)
+    .await?;
+
+    if let Some(token) = rotated_token {
+        revoke_session_after_token_reuse(db, token.token_id, token.session_id, token.user_id, client)
+            .await?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+pub(crate) async fn revoke_session_after_token_reuse(
+    db: &PgPool,
+    token_id: Uuid,
+    session_id: Uuid,
+    user_id: Uuid,
+    client: &ClientInfo,
+) -> Result<(), MyProblem> {
+    warn!("Refresh token {token_id} of session {session_id} (user {user_id}) was reused; revoking the whole session");
+
+    let mut tx = db.begin().await?;
+
+    query!(
+        "
+            UPDATE iam.token
+            SET expired_at = statement_timestamp()
+            WHERE user__id = $1
+                AND expired_at > statement_timestamp()
+                AND session_id = $2
+                AND type IN ('user_access', 'refresh')
+        ",
+        &user_id,
+        &session_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    record_security_event(
+        &mut *tx,
+        SecurityEvent::RefreshTokenReuse,
+        Some(user_id),
+        Some(session_id),
+        client,
+        Some(json!({ "token_id": token_id })),
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
@@ -0,0 +1,742 @@
+use actix_web::web::ReqData;
+use actix_web::HttpRequest;
+use biscuit_auth::Biscuit;
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, error};
+use paperclip::actix::web::{Data, Json, Path};
+use paperclip::actix::{api_v2_operation, Apiv2Schema, CreatedJson, NoContent};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_value, json, to_value, Value};
+use sqlx::types::Json as SqlxJson;
+use sqlx::{query, query_as, query_scalar, PgPool};
+use strum::AsRefStr;
+use url::Url;
+use uuid::Uuid;
+use validator::Validate;
+use webauthn_rs::fake::{FakePasskeyDistribution, WebauthnFakeCredentialGenerator};
+use webauthn_rs::prelude::{
+    Base64UrlSafeData, Passkey, PasskeyAuthentication, PasskeyRegistration, PublicKeyCredential,
+    RegisterPublicKeyCredential, WebauthnError,
+};
+use webauthn_rs::{Webauthn, WebauthnBuilder};
+
+use crate::auth::auth::{do_login, lookup_user, LoginResponse};
+use crate::auth::brute_force::record_login_attempt;
+use crate::auth::iam::{authorize_only_user, Action};
+use crate::utils::client_info::ClientInfo;
+use crate::utils::email::normalize_email;
+use crate::utils::openapi::OaBiscuitUserAccess;
+use crate::utils::problems::MyProblem;
+use crate::APP_TITLE;
+
+const CHALLENGE_EXPIRATION: Duration = Duration::minutes(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+enum ChallengeType {
+    Registration,
+    Authentication,
+}
+
+#[derive(Debug, Serialize, Apiv2Schema)]
+pub struct WebauthnChallenge {
+    /// Identifier of the ceremony, to send back when finishing it
+    challenge_id: Uuid,
+    /// Options to pass to `navigator.credentials.create()` or `navigator.credentials.get()`
+    options: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
+pub struct WebauthnRegistrationFinishPost {
+    challenge_id: Uuid,
+    /// Name given by the user to recognize this passkey
+    #[validate(non_control_character, length(min = 1, max = 100))]
+    name: String,
+    /// Credential returned by `navigator.credentials.create()`
+    credential: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
+pub struct WebauthnLoginStartPost {
+    #[validate(non_control_character, email, length(max = 100))]
+    email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema)]
+pub struct WebauthnLoginFinishPost {
+    challenge_id: Uuid,
+    /// Credential returned by `navigator.credentials.get()`
+    credential: Value,
+}
+
+#[derive(Debug, Serialize, Apiv2Schema)]
+pub struct WebauthnCredential {
+    credential_id: Uuid,
+    name: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+struct PasskeyRow {
+    credential_id: Uuid,
+    passkey: SqlxJson<Passkey>,
+}
+
+/// Build the WebAuthn relying party from the frontend application URL
+pub fn build_webauthn(app_url: &Url) -> Result<Webauthn, WebauthnError> {
+    let rp_id = app_url.host_str().ok_or(WebauthnError::Configuration)?;
+    WebauthnBuilder::new(rp_id, app_url)?
+        .rp_name(APP_TITLE)
+        .build()
+}
+
+async fn user_passkeys(db: &PgPool, user_id: Uuid) -> Result<Vec<PasskeyRow>, MyProblem> {
+    let passkeys = query_as!(
+        PasskeyRow,
+        r#"
+            SELECT webauthn_credential__id AS credential_id, passkey AS "passkey: SqlxJson<Passkey>"
+            FROM iam.webauthn_credential
+            WHERE user__id = $1
+        "#,
+        &user_id,
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(passkeys)
+}
+
+async fn store_challenge<S: Serialize>(
+    db: &PgPool,
+    challenge_type: ChallengeType,
+    user_id: Uuid,
+    ceremony_state: &S,
+) -> Result<Uuid, MyProblem> {
+    let ceremony_state = to_value(ceremony_state).map_err(|e| {
+        error!("Could not serialize WebAuthn ceremony state: {e}");
+        MyProblem::InternalServerError
+    })?;
+
+    let mut tx = db.begin().await?;
+
+    query!(
+        "
+            DELETE FROM iam.webauthn_challenge
+            WHERE user__id = $1
+                AND expired_at <= statement_timestamp()
+        ",
+        &user_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let challenge_id = query_scalar!(
+        "
+            INSERT INTO iam.webauthn_challenge (type, user__id, state, expired_at)
+            VALUES ($1, $2, $3, $4)
+            RETURNING webauthn_challenge__id AS challenge_id
+        ",
+        challenge_type.as_ref(),
+        &user_id,
+        ceremony_state,
+        Utc::now() + CHALLENGE_EXPIRATION,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(challenge_id)
+}
+
+/// Fetch and delete a pending ceremony, so that each challenge can only be answered once
+async fn consume_challenge<S: DeserializeOwned>(
+    db: &PgPool,
+    challenge_type: ChallengeType,
+    challenge_id: Uuid,
+) -> Result<Option<(Uuid, S)>, MyProblem> {
+    let challenge = query!(
+        "
+            DELETE FROM iam.webauthn_challenge
+            WHERE webauthn_challenge__id = $1
+                AND type = $2
+            RETURNING user__id AS user_id, state, expired_at
+        ",
+        &challenge_id,
+        challenge_type.as_ref(),
+    )
+    .fetch_optional(db)
+    .await?;
+
+    match challenge {
+        Some(challenge) if challenge.expired_at > Utc::now() => {
+            let ceremony_state = from_value(challenge.state).map_err(|e| {
+                error!("Could not deserialize WebAuthn ceremony state: {e}");
+                MyProblem::InternalServerError
+            })?;
+            Ok(Some((challenge.user_id, ceremony_state)))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[api_v2_operation(
+    summary = "Start passkey registration",
+    description = "Get the options to create a new passkey for the current user. The created credential must be sent using the `auth.finish_webauthn_registration` operation within 5 minutes.",
+    operation_id = "auth.start_webauthn_registration",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn start_registration(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+) -> Result<Json<WebauthnChallenge>, MyProblem> {
+    if let Ok(token) = authorize_only_user(&biscuit, Action::AuthStartWebauthnRegistration) {
+        let exclude_credentials = user_passkeys(&state.db, token.user_id)
+            .await?
+            .iter()
+            .map(|row| row.passkey.cred_id().to_owned())
+            .collect();
+
+        let (options, registration) = state
+            .webauthn
+            .start_passkey_registration(
+                token.user_id,
+                &token.email,
+                &format!("{} {}", token.first_name, token.last_name),
+                Some(exclude_credentials),
+            )
+            .map_err(|e| {
+                error!("Could not start passkey registration: {e}");
+                MyProblem::InternalServerError
+            })?;
+
+        let challenge_id = store_challenge(
+            &state.db,
+            ChallengeType::Registration,
+            token.user_id,
+            &registration,
+        )
+        .await?;
+
+        Ok(Json(WebauthnChallenge {
+            challenge_id,
+            options: to_value(options).map_err(|e| {
+                error!("Could not serialize passkey registration options: {e}");
+                MyProblem::InternalServerError
+            })?,
+        }))
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Finish passkey registration",
+    description = "Save the passkey created by the authenticator so that it can be used to log in.",
+    operation_id = "auth.finish_webauthn_registration",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn finish_registration(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+    body: Json<WebauthnRegistrationFinishPost>,
+) -> Result<CreatedJson<WebauthnCredential>, MyProblem> {
+    if let Err(e) = body.validate() {
+        return Err(MyProblem::Validation(e));
+    }
+
+    if let Ok(token) = authorize_only_user(&biscuit, Action::AuthFinishWebauthnRegistration) {
+        let body = body.into_inner();
+
+        let (user_id, registration) = consume_challenge::<PasskeyRegistration>(
+            &state.db,
+            ChallengeType::Registration,
+            body.challenge_id,
+        )
+        .await?
+        .ok_or(MyProblem::AuthWebauthnFailed)?;
+
+        if user_id != token.user_id {
+            return Err(MyProblem::AuthWebauthnFailed);
+        }
+
+        let credential: RegisterPublicKeyCredential = from_value(body.credential).map_err(|e| {
+            debug!("Invalid passkey registration credential: {e}");
+            MyProblem::AuthWebauthnFailed
+        })?;
+        let passkey = state
+            .webauthn
+            .finish_passkey_registration(&credential, &registration)
+            .map_err(|e| {
+                debug!("Passkey registration failed: {e}");
+                MyProblem::AuthWebauthnFailed
+            })?;
+
+        let credential = query_as!(
+            WebauthnCredential,
+            "
+                INSERT INTO iam.webauthn_credential (user__id, name, passkey)
+                VALUES ($1, $2, $3)
+                RETURNING webauthn_credential__id AS credential_id, name, created_at, last_used_at
+            ",
+            &token.user_id,
+            &body.name,
+            SqlxJson(&passkey) as _,
+        )
+        .fetch_one(&state.db)
+        .await?;
+
+        Ok(CreatedJson(credential))
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "List passkeys",
+    description = "List the passkeys registered by the current user.",
+    operation_id = "auth.list_webauthn_credentials",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn list_credentials(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+) -> Result<Json<Vec<WebauthnCredential>>, MyProblem> {
+    if let Ok(token) = authorize_only_user(&biscuit, Action::AuthListWebauthnCredentials) {
+        let credentials = query_as!(
+            WebauthnCredential,
+            "
+                SELECT webauthn_credential__id AS credential_id, name, created_at, last_used_at
+                FROM iam.webauthn_credential
+                WHERE user__id = $1
+                ORDER BY created_at
+            ",
+            &token.user_id,
+        )
+        .fetch_all(&state.db)
+        .await?;
+
+        Ok(Json(credentials))
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Delete a passkey",
+    description = "Delete one of the passkeys of the current user so that it can no longer be used to log in.",
+    operation_id = "auth.delete_webauthn_credential",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn delete_credential(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+    credential_id: Path<Uuid>,
+) -> Result<NoContent, MyProblem> {
+    if let Ok(token) = authorize_only_user(&biscuit, Action::AuthDeleteWebauthnCredential) {
+        query!(
+            "
+                DELETE FROM iam.webauthn_credential
+                WHERE webauthn_credential__id = $1
+                    AND user__id = $2
+                RETURNING webauthn_credential__id
+            ",
+            &credential_id.into_inner(),
+            &token.user_id,
+        )
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or(MyProblem::NotFound)?;
+
+        Ok(NoContent)
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Start passkey login",
+    description = "Get the options to sign a challenge with one of the passkeys of a user. The signed challenge must be sent using the `auth.finish_webauthn_login` operation within 5 minutes.",
+    operation_id = "auth.start_webauthn_login",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn start_login(
+    state: Data<crate::State>,
+    body: Json<WebauthnLoginStartPost>,
+) -> Result<Json<WebauthnChallenge>, MyProblem> {
+    if let Err(e) = body.validate() {
+        return Err(MyProblem::Validation(e));
+    }
+
+    let email = normalize_email(&body.email);
+
+    let user = match query!(
+        "
+            SELECT user__id AS user_id, email_verified_at
+            FROM iam.user
+            WHERE email = $1
+        ",
+        &email,
+    )
+    .fetch_optional(&state.db)
+    .await?
+    {
+        Some(user) => user,
+        None => return reject_login(&state, &email, MyProblem::AuthFailedLogin),
+    };
+
+    if user.email_verified_at.is_none() {
+        return reject_login(&state, &email, MyProblem::EmailNotVerified);
+    }
+
+    let passkeys: Vec<Passkey> = user_passkeys(&state.db, user.user_id)
+        .await?
+        .into_iter()
+        .map(|row| row.passkey.0)
+        .collect();
+
+    if passkeys.is_empty() {
+        return reject_login(&state, &email, MyProblem::AuthFailedLogin);
+    }
+
+    let (options, authentication) = state
+        .webauthn
+        .start_passkey_authentication(&passkeys)
+        .map_err(|e| {
+            error!("Could not start passkey authentication: {e}");
+            MyProblem::InternalServerError
+        })?;
+
+    let challenge_id = store_challenge(
+        &state.db,
+        ChallengeType::Authentication,
+        user.user_id,
+        &authentication,
+    )
+    .await?;
+
+    Ok(Json(WebauthnChallenge {
+        challenge_id,
+        options: to_value(options).map_err(|e| {
+            error!("Could not serialize passkey authentication options: {e}");
+            MyProblem::InternalServerError
+        })?,
+    }))
+}
+
+/// Fail to start a passkey login, or pretend to start it when enumeration protection is enabled
+///
+/// The fake options list credential IDs derived from the email address, so that they are the same on every attempt, like the ones of an actual account.
+/// The challenge is not stored, so finishing the login fails like it does with a wrong passkey.
+fn reject_login(
+    state: &crate::State,
+    email: &str,
+    problem: MyProblem,
+) -> Result<Json<WebauthnChallenge>, MyProblem> {
+    if !state.enumeration_protection {
+        return Err(problem);
+    }
+
+    let credential_ids = WebauthnFakeCredentialGenerator::<FakePasskeyDistribution>::new(
+        &state.biscuit_private_key.to_bytes(),
+    )
+    .and_then(|generator| generator.generate(email.as_bytes()))
+    .map_err(|e| {
+        error!("Could not generate fake passkey credentials: {e}");
+        MyProblem::InternalServerError
+    })?;
+
+    let (options, _) = state
+        .webauthn
+        .start_passkey_authentication(&[])
+        .map_err(|e| {
+            error!("Could not start passkey authentication: {e}");
+            MyProblem::InternalServerError
+        })?;
+
+    let mut options = to_value(options).map_err(|e| {
+        error!("Could not serialize passkey authentication options: {e}");
+        MyProblem::InternalServerError
+    })?;
+    options["publicKey"]["allowCredentials"] = credential_ids
+        .iter()
+        .map(|id| json!({ "type": "public-key", "id": Base64UrlSafeData::from(id.as_ref()) }))
+        .collect();
+
+    Ok(Json(WebauthnChallenge {
+        challenge_id: Uuid::new_v4(),
+        options,
+    }))
+}
+
+#[api_v2_operation(
+    summary = "Finish passkey login",
+    description = "Get an access token by sending a challenge signed with one of the user's passkeys. The response is the same as the one of the `auth.login` operation.",
+    operation_id = "auth.finish_webauthn_login",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("Authentication")
+)]
+pub async fn finish_login(
+    state: Data<crate::State>,
+    req: HttpRequest,
+    body: Json<WebauthnLoginFinishPost>,
+) -> Result<CreatedJson<LoginResponse>, MyProblem> {
+    let body = body.into_inner();
+
+    let (user_id, authentication) = consume_challenge::<PasskeyAuthentication>(
+        &state.db,
+        ChallengeType::Authentication,
+        body.challenge_id,
+    )
+    .await?
+    .ok_or(MyProblem::AuthFailedLogin)?;
+
+    let credential: PublicKeyCredential = from_value(body.credential).map_err(|e| {
+        debug!("Invalid passkey authentication credential: {e}");
+        MyProblem::AuthFailedLogin
+    })?;
+    let result = state
+        .webauthn
+        .finish_passkey_authentication(&credential, &authentication)
+        .map_err(|e| {
+            debug!("Passkey authentication failed: {e}");
+            MyProblem::AuthFailedLogin
+        })?;
+
+    let mut tx = state.db.begin().await?;
+
+    let passkey = query_as!(
+        PasskeyRow,
+        r#"
+            SELECT webauthn_credential__id AS credential_id, passkey AS "passkey: SqlxJson<Passkey>"
+            FROM iam.webauthn_credential
+            WHERE user__id = $1
+            FOR UPDATE
+        "#,
+        &user_id,
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .find(|row| row.passkey.cred_id() == result.cred_id())
+    .ok_or(MyProblem::AuthFailedLogin)?;
+
+    // Keep the signature counter and backup state up to date so that cloned authenticators can be detected
+    let mut updated_passkey = passkey.passkey.0;
+    updated_passkey.update_credential(&result);
+
+    query!(
+        "
+            UPDATE iam.webauthn_credential
+            SET passkey = $1, last_used_at = statement_timestamp()
+            WHERE webauthn_credential__id = $2
+        ",
+        SqlxJson(&updated_passkey) as _,
+        &passkey.credential_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let user = lookup_user(&mut tx, user_id).await?;
+    let email = user.email.clone();
+    let client = ClientInfo::from_request(&req, state.trust_proxy_headers);
+    let res = do_login(&mut tx, &state.biscuit_private_key, user, None, &client).await?;
+
+    tx.commit().await?;
+    record_login_attempt(&state, &email, &client, true).await?;
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use webauthn_authenticator_rs::softpasskey::SoftPasskey;
+    use webauthn_authenticator_rs::WebauthnAuthenticator;
+
+    use super::*;
+    use crate::utils::testing;
+
+    fn app_url() -> Url {
+        Url::parse("https://app.example.com").unwrap()
+    }
+
+    async fn stored_passkey(db: &PgPool, user_id: Uuid) -> (Value, Option<DateTime<Utc>>) {
+        let credential = query!(
+            r#"
+                SELECT passkey AS "passkey!: Value", last_used_at
+                FROM iam.webauthn_credential
+                WHERE user__id = $1
+            "#,
+            &user_id,
+        )
+        .fetch_one(db)
+        .await
+        .unwrap();
+
+        (credential.passkey, credential.last_used_at)
+    }
+
+    #[sqlx::test]
+    async fn passkey_registration_and_login(db: PgPool) {
+        let state = Data::new(testing::state(db.clone()));
+        let user_id = testing::create_user(&db, "user@example.com").await;
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+
+        let challenge = start_registration(
+            state.clone(),
+            OaBiscuitUserAccess,
+            testing::user_biscuit(&state, user_id).await,
+        )
+        .await
+        .unwrap()
+        .0;
+        let credential = authenticator
+            .do_registration(app_url(), from_value(challenge.options).unwrap())
+            .unwrap();
+        let registration = || {
+            Json(WebauthnRegistrationFinishPost {
+                challenge_id: challenge.challenge_id,
+                name: "Laptop".to_owned(),
+                credential: to_value(&credential).unwrap(),
+            })
+        };
+
+        finish_registration(
+            state.clone(),
+            OaBiscuitUserAccess,
+            testing::user_biscuit(&state, user_id).await,
+            registration(),
+        )
+        .await
+        .unwrap();
+        let (registered_passkey, last_used_at) = stored_passkey(&db, user_id).await;
+        assert!(last_used_at.is_none());
+
+        // Each challenge can only be answered once
+        assert!(matches!(
+            finish_registration(
+                state.clone(),
+                OaBiscuitUserAccess,
+                testing::user_biscuit(&state, user_id).await,
+                registration(),
+            )
+            .await,
+            Err(MyProblem::AuthWebauthnFailed)
+        ));
+
+        let challenge = start_login(
+            state.clone(),
+            Json(WebauthnLoginStartPost {
+                email: "User@Example.com".to_owned(),
+            }),
+        )
+        .await
+        .unwrap()
+        .0;
+        let credential = authenticator
+            .do_authentication(app_url(), from_value(challenge.options).unwrap())
+            .unwrap();
+        let login = || {
+            Json(WebauthnLoginFinishPost {
+                challenge_id: challenge.challenge_id,
+                credential: to_value(&credential).unwrap(),
+            })
+        };
+
+        let res = finish_login(
+            state.clone(),
+            TestRequest::default().to_http_request(),
+            login(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(to_value(&res.0).unwrap()["user_id"], json!(user_id));
+
+        // The signature counter is saved so that a cloned authenticator would be detected
+        let (used_passkey, last_used_at) = stored_passkey(&db, user_id).await;
+        assert!(last_used_at.is_some());
+        let counter = |passkey: &Value| passkey["cred"]["counter"].as_u64().unwrap();
+        assert!(counter(&used_passkey) > counter(&registered_passkey));
+
+        assert!(matches!(
+            finish_login(
+                state.clone(),
+                TestRequest::default().to_http_request(),
+                login()
+            )
+            .await,
+            Err(MyProblem::AuthFailedLogin)
+        ));
+    }
+
+    #[sqlx::test]
+    async fn unknown_accounts_are_rejected_without_enumeration_protection(db: PgPool) {
+        let state = Data::new(testing::state(db));
+
+        let res = start_login(
+            state,
+            Json(WebauthnLoginStartPost {
+                email: "nobody@example.com".to_owned(),
+            }),
+        )
+        .await;
+
+        assert!(matches!(res, Err(MyProblem::AuthFailedLogin)));
+    }
+
+    #[sqlx::test]
+    async fn unknown_accounts_get_fake_options_with_enumeration_protection(db: PgPool) {
+        let mut state = testing::state(db);
+        state.enumeration_protection = true;
+        let state = Data::new(state);
+
+        let start = |email: &str| {
+            start_login(
+                state.clone(),
+                Json(WebauthnLoginStartPost {
+                    email: email.to_owned(),
+                }),
+            )
+        };
+        let first = start("nobody@example.com").await.unwrap().0;
+        let second = start("Nobody@Example.com").await.unwrap().0;
+
+        // Credentials look like those of an actual account, which does not change from one attempt to the other.
+        // Some accounts have no passkey, so only some of the others differ.
+        let credentials = |challenge: &WebauthnChallenge| {
+            challenge.options["publicKey"]["allowCredentials"].clone()
+        };
+        assert_eq!(credentials(&first), credentials(&second));
+        let mut others = Vec::new();
+        for i in 0..10 {
+            let other = start(&format!("nobody{i}@example.com")).await.unwrap().0;
+            others.push(credentials(&other));
+        }
+        assert!(others.iter().any(|other| *other != credentials(&first)));
+        assert_ne!(first.challenge_id, second.challenge_id);
+
+        // The challenge is not stored, so the login fails as with a wrong passkey
+        let res = finish_login(
+            state,
+            TestRequest::default().to_http_request(),
+            Json(WebauthnLoginFinishPost {
+                challenge_id: first.challenge_id,
+                credential: json!({}),
+            }),
+        )
+        .await;
+        assert!(matches!(res, Err(MyProblem::AuthFailedLogin)));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,566 @@
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType, HeaderValue, CACHE_CONTROL};
+use actix_web::web::{block, Query, ReqData};
+use actix_web::HttpResponse;
+use biscuit_auth::Biscuit;
+use chrono::{DateTime, Utc};
+use lettre::message::Mailbox;
+use lettre::Address;
+use log::{debug, error, info, warn};
+use paperclip::actix::web::Data;
+use paperclip::actix::{api_v2_operation, AcceptedJson, Apiv2Schema};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{query, query_as, query_scalar, PgPool};
+use std::io::{Cursor, Write};
+use std::str::FromStr;
+use std::time::SystemTime;
+use uuid::Uuid;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::auth::iam::{authorize_data_export_download, authorize_only_user, create_data_export_download_token, Action};
+use crate::utils::locale::Locale;
+use crate::utils::mailer::Mail;
+use crate::utils::openapi::OaBiscuitUserAccess;
+use crate::utils::problems::MyProblem;
+use crate::utils::storage::Storage;
+
+use super::profile_picture::{profile_picture_key, ProfilePictureFormat, IMAGE_SIZE};
+
+/// Minimum number of days between two data export requests of a user
+const DATA_EXPORT_REQUEST_INTERVAL_IN_DAYS: i64 = 1;
+/// Number of days during which a data export can be downloaded
+const DATA_EXPORT_EXPIRATION_IN_DAYS: i32 = 7;
+/// Number of minutes after which a data export that is still not generated is considered interrupted, for example by a restart
+const DATA_EXPORT_GENERATION_TIMEOUT_IN_MINUTES: i64 = 60;
+
+#[derive(Debug, Serialize, Apiv2Schema)]
+pub struct DataExport {
+    data_export_id: Uuid,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedUser {
+    user_id: Uuid,
+    email: String,
+    first_name: String,
+    last_name: String,
+    display_name: Option<String>,
+    bio: Option<String>,
+    locale: Option<String>,
+    timezone: Option<String>,
+    role: String,
+    created_at: DateTime<Utc>,
+    email_verified_at: Option<DateTime<Utc>>,
+    last_login: Option<DateTime<Utc>>,
+    disabled_at: Option<DateTime<Utc>>,
+    totp_enabled_at: Option<DateTime<Utc>>,
+    deletion_requested_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedSession {
+    session_id: Uuid,
+    created_at: DateTime<Utc>,
+    last_refreshed_at: Option<DateTime<Utc>>,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedToken {
+    token_id: Uuid,
+    token_type: String,
+    session_id: Option<Uuid>,
+    created_at: DateTime<Utc>,
+    expired_at: Option<DateTime<Utc>>,
+    rotated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedPasskey {
+    name: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedRecoveryCode {
+    created_at: DateTime<Utc>,
+    used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedSecurityEvent {
+    event_type: String,
+    session_id: Option<Uuid>,
+    ip_address: Option<String>,
+    details: Option<Value>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedAuthAttempt {
+    attempt_type: String,
+    ip_address: Option<String>,
+    succeeded: bool,
+    created_at: DateTime<Utc>,
+}
+
+/// Everything stored about a user, except secrets such as the password hash, the TOTP secret or the tokens themselves
+#[derive(Debug, Serialize)]
+struct PersonalData {
+    exported_at: DateTime<Utc>,
+    user: ExportedUser,
+    sessions: Vec<ExportedSession>,
+    tokens: Vec<ExportedToken>,
+    passkeys: Vec<ExportedPasskey>,
+    recovery_codes: Vec<ExportedRecoveryCode>,
+    security_events: Vec<ExportedSecurityEvent>,
+    auth_attempts: Vec<ExportedAuthAttempt>,
+}
+
+fn data_export_key(data_export_id: Uuid) -> String {
+    format!("data-exports/{data_export_id}.zip")
+}
+
+#[api_v2_operation(
+    summary = "Request data export",
+    description = "Request an archive of all the personal data stored about the user. The archive is generated in the background and a download link is sent by email. Only one export can be requested per day.",
+    operation_id = "user_settings.request_data_export",
+    produces = "application/json",
+    tags("UserSettings")
+)]
+pub async fn request_data_export(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+) -> Result<AcceptedJson<DataExport>, MyProblem> {
+    if let Ok(token) = authorize_only_user(&biscuit, Action::UserSettingsRequestDataExport) {
+        let mut tx = state.db.begin().await?;
+
+        // Lock the user so that concurrent requests cannot bypass the request interval
+        query!(
+            "
+                SELECT user__id
+                FROM iam.user
+                WHERE user__id = $1
+                FOR UPDATE
+            ",
+            &token.user_id,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // Interrupted exports do not count, even before the purge job marks them as failed
+        let last_requested_at = query_scalar!(
+            "
+                SELECT max(created_at)
+                FROM iam.data_export
+                WHERE user__id = $1
+                    AND failed_at IS NULL
+                    AND (completed_at IS NOT NULL OR created_at > $2)
+            ",
+            &token.user_id,
+            Utc::now() - chrono::Duration::minutes(DATA_EXPORT_GENERATION_TIMEOUT_IN_MINUTES),
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        if let Some(last_requested_at) = last_requested_at {
+            let next_request_at =
+                last_requested_at + chrono::Duration::days(DATA_EXPORT_REQUEST_INTERVAL_IN_DAYS);
+            let now = Utc::now();
+            if next_request_at > now {
+                return Err(MyProblem::TooManyRequests(
+                    (next_request_at - now).num_seconds().max(1) as u64,
+                ));
+            }
+        }
+
+        let data_export_id = query_scalar!(
+            "
+                INSERT INTO iam.data_export (user__id, expired_at)
+                VALUES ($1, statement_timestamp() + make_interval(days => $2))
+                RETURNING data_export__id
+            ",
+            &token.user_id,
+            DATA_EXPORT_EXPIRATION_IN_DAYS,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        let state = state.clone();
+        let user_id = token.user_id;
+        actix_web::rt::spawn(async move {
+            if let Err(e) = generate_data_export(&state, user_id, data_export_id).await {
+                error!("Could not generate data export {data_export_id}: {e}");
+                if let Err(e) = query!(
+                    "
+                        UPDATE iam.data_export
+                        SET failed_at = statement_timestamp()
+                        WHERE data_export__id = $1
+                    ",
+                    &data_export_id,
+                )
+                .execute(&state.db)
+                .await
+                {
+                    error!("Could not mark data export {data_export_id} as failed: {e}");
+                }
+            }
+        });
+
+        Ok(AcceptedJson(DataExport { data_export_id }))
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+/// Assemble the archive of a data export, store it and send its download link to the user
+async fn generate_data_export(
+    state: &Data<crate::State>,
+    user_id: Uuid,
+    data_export_id: Uuid,
+) -> Result<(), MyProblem> {
+    let personal_data = collect_personal_data(&state.db, user_id).await?;
+    let profile_picture_version = query_scalar!(
+        "
+            SELECT profile_picture_version
+            FROM iam.user
+            WHERE user__id = $1
+        ",
+        &user_id,
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .flatten();
+    let profile_picture = match profile_picture_version {
+        Some(version) => {
+            state
+                .storage
+                .get(&profile_picture_key(user_id, version, IMAGE_SIZE, ProfilePictureFormat::Jpeg))
+                .await?
+        }
+        None => None,
+    };
+
+    let email = personal_data.user.email.clone();
+    let name = format!("{} {}", personal_data.user.first_name, personal_data.user.last_name);
+    let locale = Locale::from_stored(personal_data.user.locale.as_deref());
+
+    let archive = block(move || {
+        build_archive(&personal_data, profile_picture.map(|picture| picture.content))
+    })
+    .await
+    .map_err(|e| {
+        error!("Error trying to build data export archive: {e}");
+        MyProblem::InternalServerError
+    })??;
+
+    state
+        .storage
+        .put(&data_export_key(data_export_id), archive, "application/zip")
+        .await?;
+
+    let mut tx = state.db.begin().await?;
+
+    let expired_at = query_scalar!(
+        "
+            UPDATE iam.data_export
+            SET completed_at = statement_timestamp()
+            WHERE data_export__id = $1 AND failed_at IS NULL
+            RETURNING expired_at
+        ",
+        &data_export_id,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let download_token = create_data_export_download_token(
+        &state.biscuit_private_key,
+        user_id,
+        data_export_id,
+        SystemTime::from(expired_at),
+    )
+    .map_err(|e| {
+        error!("Error trying to create data export download token: {e}");
+        MyProblem::InternalServerError
+    })?;
+    let address = Address::from_str(&email).map_err(|e| {
+        error!("Error trying to parse email address: {e}");
+        MyProblem::InternalServerError
+    })?;
+
+    state
+        .mailer
+        .queue_mail(
+            &mut *tx,
+            Mail::DataExportReady {
+                url: format!(
+                    "{}api/v1/user/data-export/download?token={}",
+                    state.api_url, &download_token.serialized_biscuit
+                ),
+                days: DATA_EXPORT_EXPIRATION_IN_DAYS.into(),
+            },
+            Mailbox::new(Some(name), address),
+            locale,
+        )
+        .await?;
+
+    tx.commit().await?;
+
+    info!("Data export {data_export_id} of user {user_id} is ready");
+
+    Ok(())
+}
+
+async fn collect_personal_data(db: &PgPool, user_id: Uuid) -> Result<PersonalData, MyProblem> {
+    let user = query_as!(
+        ExportedUser,
+        "
+            SELECT user__id AS user_id, email, first_name, last_name, display_name, bio, locale, timezone, role, created_at, email_verified_at, last_login, disabled_at, totp_enabled_at, deletion_requested_at
+            FROM iam.user
+            WHERE user__id = $1
+        ",
+        &user_id,
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or(MyProblem::NotFound)?;
+
+    let sessions = query_as!(
+        ExportedSession,
+        "
+            SELECT session__id AS session_id, created_at, last_refreshed_at, user_agent, host(ip_address) AS ip_address
+            FROM iam.session
+            WHERE user__id = $1
+            ORDER BY created_at
+        ",
+        &user_id,
+    )
+    .fetch_all(db)
+    .await?;
+
+    let tokens = query_as!(
+        ExportedToken,
+        "
+            SELECT token__id AS token_id, type AS token_type, session_id, created_at, expired_at, rotated_at
+            FROM iam.token
+            WHERE user__id = $1
+            ORDER BY created_at
+        ",
+        &user_id,
+    )
+    .fetch_all(db)
+    .await?;
+
+    let passkeys = query_as!(
+        ExportedPasskey,
+        "
+            SELECT name, created_at, last_used_at
+            FROM iam.webauthn_credential
+            WHERE user__id = $1
+            ORDER BY created_at
+        ",
+        &user_id,
+    )
+    .fetch_all(db)
+    .await?;
+
+    let recovery_codes = query_as!(
+        ExportedRecoveryCode,
+        "
+            SELECT created_at, used_at
+            FROM iam.recovery_code
+            WHERE user__id = $1
+            ORDER BY created_at
+        ",
+        &user_id,
+    )
+    .fetch_all(db)
+    .await?;
+
+    let security_events = query_as!(
+        ExportedSecurityEvent,
+        "
+            SELECT type AS event_type, session_id, host(ip_address) AS ip_address, details, created_at
+            FROM iam.security_event
+            WHERE user__id = $1
+            ORDER BY created_at
+        ",
+        &user_id,
+    )
+    .fetch_all(db)
+    .await?;
+
+    // Authentication attempts are only linked to users by their email address
+    let auth_attempts = query_as!(
+        ExportedAuthAttempt,
+        "
+            SELECT type AS attempt_type, host(ip_address) AS ip_address, succeeded, created_at
+            FROM iam.auth_attempt
+            WHERE email = $1
+            ORDER BY created_at
+        ",
+        &user.email,
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(PersonalData {
+        exported_at: Utc::now(),
+        user,
+        sessions,
+        tokens,
+        passkeys,
+        recovery_codes,
+        security_events,
+        auth_attempts,
+    })
+}
+
+fn build_archive(
+    personal_data: &PersonalData,
+    profile_picture: Option<Vec<u8>>,
+) -> Result<Vec<u8>, MyProblem> {
+    let json = serde_json::to_vec_pretty(personal_data).map_err(|e| {
+        error!("Error trying to serialize personal data: {e}");
+        MyProblem::InternalServerError
+    })?;
+
+    let result = (|| -> zip::result::ZipResult<Vec<u8>> {
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+
+        zip.start_file("personal-data.json", options)?;
+        zip.write_all(&json)?;
+
+        if let Some(profile_picture) = profile_picture {
+            // JPEG is already compressed
+            zip.start_file(
+                "profile-picture.jpeg",
+                options.compression_method(CompressionMethod::Stored),
+            )?;
+            zip.write_all(&profile_picture)?;
+        }
+
+        Ok(zip.finish()?.into_inner())
+    })();
+
+    result.map_err(|e| {
+        error!("Error trying to write data export archive: {e}");
+        MyProblem::InternalServerError
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DataExportDownloadQs {
+    token: String,
+}
+
+/// Download a data export using the link sent by email; the token replaces the authorization header so that the link can be opened in a browser
+pub async fn download_data_export(
+    state: Data<crate::State>,
+    qs: Query<DataExportDownloadQs>,
+) -> Result<HttpResponse, MyProblem> {
+    let biscuit = Biscuit::from_base64(&qs.token, state.biscuit_private_key.public()).map_err(|e| {
+        debug!("{e}");
+        MyProblem::AuthEmailExpired
+    })?;
+    let token = authorize_data_export_download(&biscuit).map_err(|e| {
+        debug!("{e}");
+        MyProblem::AuthEmailExpired
+    })?;
+
+    let data_export = query!(
+        r#"
+            SELECT completed_at AS "completed_at!"
+            FROM iam.data_export
+            WHERE data_export__id = $1
+                AND user__id = $2
+                AND completed_at IS NOT NULL
+                AND expired_at > statement_timestamp()
+        "#,
+        &token.data_export_id,
+        &token.user_id,
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(MyProblem::NotFound)?;
+
+    let archive = state
+        .storage
+        .get(&data_export_key(token.data_export_id))
+        .await?
+        .ok_or(MyProblem::NotFound)?;
+
+    let filename = format!(
+        "personal-data-{}.zip",
+        data_export.completed_at.format("%Y-%m-%d")
+    );
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(filename)],
+        })
+        .insert_header((CACHE_CONTROL, HeaderValue::from_static("private, no-store")))
+        .body(archive.content))
+}
+
+/// Mark the data exports whose generation was interrupted as failed, so that they can be requested again
+pub async fn fail_interrupted_data_exports(db: &PgPool) -> Result<u64, MyProblem> {
+    let failed = query!(
+        "
+            UPDATE iam.data_export
+            SET failed_at = statement_timestamp()
+            WHERE completed_at IS NULL
+                AND failed_at IS NULL
+                AND created_at <= $1
+        ",
+        Utc::now() - chrono::Duration::minutes(DATA_EXPORT_GENERATION_TIMEOUT_IN_MINUTES),
+    )
+    .execute(db)
+    .await?
+    .rows_affected();
+
+    Ok(failed)
+}
+
+/// Remove the archives of expired data exports and of deleted users from the storage
+pub async fn purge_expired_data_exports(
+    db: &PgPool,
+    storage: &dyn Storage,
+) -> Result<usize, MyProblem> {
+    let data_export_ids = query_scalar!(
+        "
+            SELECT data_export__id
+            FROM iam.data_export
+            WHERE expired_at <= statement_timestamp() OR user__id IS NULL
+        "
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut purged = 0;
+    for data_export_id in data_export_ids {
+        if storage.delete(&data_export_key(data_export_id)).await.is_err() {
+            warn!("Archive of data export {data_export_id} could not be removed");
+            continue;
+        }
+
+        query!(
+            "DELETE FROM iam.data_export WHERE data_export__id = $1",
+            &data_export_id
+        )
+        .execute(db)
+        .await?;
+        purged += 1;
+    }
+
+    Ok(purged)
+}
\ No newline at end of file
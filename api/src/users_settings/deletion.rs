@@ -0,0 +1,68 @@
+use actix_web::rt::time::interval;
+use chrono::Utc;
+use log::{error, info, warn};
+use sqlx::{query, PgPool};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::utils::problems::MyProblem;
+use crate::utils::storage::Storage;
+
+use super::data_export::{fail_interrupted_data_exports, purge_expired_data_exports};
+use super::profile_picture::discard_profile_pictures;
+
+/// Permanently delete the accounts whose deletion was requested more than `grace_period` ago
+pub async fn purge_deleted_accounts(
+    db: &PgPool,
+    storage: &dyn Storage,
+    grace_period: chrono::Duration,
+) -> Result<usize, MyProblem> {
+    let deleted_users = query!(
+        "
+            DELETE FROM iam.user
+            WHERE deletion_requested_at <= $1
+            RETURNING user__id AS user_id, profile_picture_version
+        ",
+        Utc::now() - grace_period,
+    )
+    .fetch_all(db)
+    .await?;
+
+    for user in &deleted_users {
+        if let Some(version) = user.profile_picture_version {
+            discard_profile_pictures(storage, user.user_id, version).await;
+        }
+    }
+
+    Ok(deleted_users.len())
+}
+
+/// Run `purge_deleted_accounts`, `fail_interrupted_data_exports` then `purge_expired_data_exports` every `every` in the background
+pub fn spawn_purge_job(
+    db: PgPool,
+    storage: Arc<dyn Storage>,
+    grace_period: chrono::Duration,
+    every: Duration,
+) {
+    actix_web::rt::spawn(async move {
+        let mut ticker = interval(every);
+        loop {
+            ticker.tick().await;
+            match purge_deleted_accounts(&db, storage.as_ref(), grace_period).await {
+                Ok(0) => {}
+                Ok(count) => info!("Purged {count} deleted account(s)"),
+                Err(e) => error!("Error trying to purge deleted accounts: {e}"),
+            }
+            match fail_interrupted_data_exports(&db).await {
+                Ok(0) => {}
+                Ok(count) => warn!("Marked {count} interrupted data export(s) as failed"),
+                Err(e) => error!("Error trying to fail interrupted data exports: {e}"),
+            }
+            match purge_expired_data_exports(&db, storage.as_ref()).await {
+                Ok(0) => {}
+                Ok(count) => info!("Purged {count} expired data export(s)"),
+                Err(e) => error!("Error trying to purge expired data exports: {e}"),
+            }
+        }
+    });
+}
\ No newline at end of file
@@ -1,118 +1,408 @@
 use actix_web::web::{Json, ReqData};
-use actix_multipart::Multipart;
+use actix_web::HttpRequest;
 use biscuit_auth::Biscuit;
-use futures_util::TryStreamExt;
-use log::{error, warn};
-use mime::{Mime, IMAGE_JPEG, IMAGE_PNG};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use lettre::message::Mailbox;
+use lettre::Address;
+use log::{debug, error};
 use paperclip::actix::web::Data;
 use paperclip::actix::{api_v2_operation, Apiv2Schema, NoContent};
 use serde::{Deserialize, Serialize};
-use sqlx::query;
-use validator::Validate;
-use std::fs;
-use std::io::Write;
+use sqlx::{query, query_as, query_scalar};
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
+use std::str::FromStr;
 
+use crate::utils::email::normalize_email;
+use crate::utils::locale::Locale;
+use crate::utils::mailer::Mail;
 use crate::utils::problems::MyProblem;
-use crate::auth::iam::{authorize_only_user, Action};
+use crate::auth::auth::check_password;
+use crate::auth::iam::{authorize_only_user, create_account_deletion_cancel_token, create_email_change_token, create_email_change_undo_token, Action};
+use crate::auth::sessions::revoke_user_sessions;
 use crate::utils::openapi::OaBiscuitUserAccess;
 
-#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
-pub struct ChangeNamePost {
-    #[validate(non_control_character, length(min = 1, max = 20))]
+struct UserProfileRow {
+    user_id: Uuid,
+    email: String,
+    first_name: String,
+    last_name: String,
+    display_name: Option<String>,
+    bio: Option<String>,
+    locale: Option<String>,
+    timezone: Option<String>,
+    created_at: DateTime<Utc>,
+    email_verified_at: Option<DateTime<Utc>>,
+    last_login: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Apiv2Schema)]
+pub struct UserProfile {
+    user_id: Uuid,
+    email: String,
     first_name: String,
-    #[validate(non_control_character, length(min = 1, max = 20))]
     last_name: String,
+    display_name: Option<String>,
+    bio: Option<String>,
+    locale: Option<String>,
+    timezone: Option<String>,
+    profile_picture_url: String,
+    created_at: DateTime<Utc>,
+    email_verified_at: Option<DateTime<Utc>>,
+    last_login: Option<DateTime<Utc>>,
+}
+
+impl UserProfile {
+    fn from_row(row: UserProfileRow) -> Self {
+        // A generated avatar is served when the user has no profile picture
+        let profile_picture_url = format!("/api/v1/user/{}/profile-picture", row.user_id);
+
+        UserProfile {
+            user_id: row.user_id,
+            email: row.email,
+            first_name: row.first_name,
+            last_name: row.last_name,
+            display_name: row.display_name,
+            bio: row.bio,
+            locale: row.locale,
+            timezone: row.timezone,
+            profile_picture_url,
+            created_at: row.created_at,
+            email_verified_at: row.email_verified_at,
+            last_login: row.last_login,
+        }
+    }
+}
+
+/// Fields that are not given are left unchanged; an empty string removes an optional field
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
+pub struct UpdateProfilePatch {
+    #[validate(non_control_character, length(min = 1, max = 50))]
+    first_name: Option<String>,
+    #[validate(non_control_character, length(min = 1, max = 50))]
+    last_name: Option<String>,
+    #[validate(non_control_character, length(max = 50))]
+    display_name: Option<String>,
+    #[validate(custom = "validate_bio", length(max = 500))]
+    bio: Option<String>,
+    #[validate(custom = "validate_locale")]
+    locale: Option<String>,
+    #[validate(custom = "validate_timezone")]
+    timezone: Option<String>,
+}
+
+fn validate_bio(bio: &str) -> Result<(), ValidationError> {
+    if bio.chars().any(|c| c.is_control() && c != '\n' && c != '\r' && c != '\t') {
+        return Err(ValidationError::new("bio"));
+    }
+
+    Ok(())
+}
+
+/// Accept BCP 47 language tags such as `fr` or `en-US`
+fn validate_locale(locale: &str) -> Result<(), ValidationError> {
+    if locale.is_empty() {
+        return Ok(());
+    }
+
+    let mut subtags = locale.split('-');
+    let language_is_valid = subtags
+        .next()
+        .map(|language| (2..=3).contains(&language.len()) && language.chars().all(|c| c.is_ascii_alphabetic()))
+        .unwrap_or(false);
+    let subtags_are_valid = subtags.all(|subtag| {
+        (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+    });
+
+    if locale.len() <= 35 && language_is_valid && subtags_are_valid {
+        Ok(())
+    } else {
+        Err(ValidationError::new("locale"))
+    }
+}
+
+/// Accept IANA time zone names such as `Europe/Paris`
+fn validate_timezone(timezone: &str) -> Result<(), ValidationError> {
+    if timezone.is_empty() || Tz::from_str(timezone).is_ok() {
+        Ok(())
+    } else {
+        Err(ValidationError::new("timezone"))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
+pub struct DeleteUserBody {
+    #[validate(non_control_character, length(min = 1, max = 100))]
+    password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Apiv2Schema, Validate)]
+pub struct ChangeEmailPost {
+    #[validate(non_control_character, email, length(max = 100))]
+    new_email: String,
+    #[validate(non_control_character, length(min = 1, max = 100))]
+    password: String,
 }
 
-const MAX_FILE_COUNT: usize = 1;
-const MAX_FILE_SIZE: usize = 1024 * 1024 * 5; // Todo: Add file limit
-const IMAGE_SIZE: (u32, u32) = (200, 200); // Todo: Add resize image
-const ALLOWED_FILE_TYPES: [Mime; 2] = [IMAGE_JPEG, IMAGE_PNG];
-/* #[api_v2_operation(
-    summary = "Change profile picture",
-    description = "Change the profile picture of a user.",
-    operation_id = "user_settings.change_profile_picture",
-    consumes = "multipart/form-data",
+#[api_v2_operation(
+    summary = "Get profile",
+    description = "Get the profile of the user.",
+    operation_id = "user_settings.get_profile",
     produces = "application/json",
     tags("UserSettings")
-)] */
-pub async fn change_profile_picture(
+)]
+pub async fn get_profile(
     state: Data<crate::State>,
     _: OaBiscuitUserAccess,
     biscuit: ReqData<Biscuit>,
-    mut payload: Multipart,
-) -> Result<NoContent, MyProblem> {
+) -> Result<Json<UserProfile>, MyProblem> {
     if let Ok(token) = authorize_only_user(
         &biscuit,
-        Action::UserSettingsChangeProfilePicture,
+        Action::UserSettingsGetProfile,
     ) {
-        let mut current_count = 0;
-        loop {
-            if current_count == MAX_FILE_COUNT { break; }
-            if let Some(mut field) = payload.try_next().await.map_err(|e| {
-                error!("Error trying to read profile picture: {e}");
-                MyProblem::InternalServerError
-            })? {
-                let filetype: Option<&Mime> = field.content_type();
-                if filetype.is_none() { continue; }
-                if !ALLOWED_FILE_TYPES.contains(&filetype.unwrap()) { continue; }
-
-                let destination: String = format!(
-                    "{}{}.jpeg",
-                    state.profile_picture_dir,
-                    token.user_id
-                );
-    
-                let mut saved_file: fs::File = fs::File::create(&destination).map_err(|e| {
-                    error!("Error trying to create profile picture: {e}");
-                    MyProblem::InternalServerError
-                })?;
-                while let Ok(Some(chunk)) = field.try_next().await {
-                    let _ = saved_file.write_all(&chunk).map_err(|e| {
-                        error!("Error trying to write profile picture: {e}");
-                        MyProblem::InternalServerError
-                    })?;
-                }
-                
-            } else { 
-                warn!("No profile picture found in the request");
-                break;
-             }
-            current_count += 1;
-        }
+        let row = query_as!(
+            UserProfileRow,
+            "
+                SELECT user__id AS user_id, email, first_name, last_name, display_name, bio, locale, timezone, created_at, email_verified_at, last_login
+                FROM iam.user
+                WHERE user__id = $1
+            ",
+            &token.user_id,
+        )
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or(MyProblem::NotFound)?;
 
-        Ok(NoContent)
+        Ok(Json(UserProfile::from_row(row)))
     } else {
         Err(MyProblem::Forbidden)
     }
 }
 
 #[api_v2_operation(
-    summary = "Change name",
-    description = "Change the name of the user.",
-    operation_id = "user_settings.change_name",
+    summary = "Update profile",
+    description = "Update the profile of the user. Fields that are not given are left unchanged and an empty string removes an optional field.",
+    operation_id = "user_settings.update_profile",
+    consumes = "application/json",
     produces = "application/json",
     tags("UserSettings")
 )]
-pub async fn change_name(
+pub async fn update_profile(
     state: Data<crate::State>,
     _: OaBiscuitUserAccess,
     biscuit: ReqData<Biscuit>,
-    body: Json<ChangeNamePost>,
-) -> Result<NoContent, MyProblem> {
+    body: Json<UpdateProfilePatch>,
+) -> Result<Json<UserProfile>, MyProblem> {
+    if let Err(e) = body.validate() {
+        return Err(MyProblem::Validation(e));
+    }
+
     if let Ok(token) = authorize_only_user(
         &biscuit,
-        Action::UserSettingsChangeName,
+        Action::UserSettingsUpdateProfile,
     ) {
-        query!(
-            "UPDATE iam.user SET first_name = $1, last_name = $2 WHERE user__id = $3",
+        let row = query_as!(
+            UserProfileRow,
+            "
+                UPDATE iam.user
+                SET first_name = COALESCE($1, first_name),
+                    last_name = COALESCE($2, last_name),
+                    display_name = CASE WHEN $3::text IS NULL THEN display_name ELSE NULLIF($3, '') END,
+                    bio = CASE WHEN $4::text IS NULL THEN bio ELSE NULLIF($4, '') END,
+                    locale = CASE WHEN $5::text IS NULL THEN locale ELSE NULLIF($5, '') END,
+                    timezone = CASE WHEN $6::text IS NULL THEN timezone ELSE NULLIF($6, '') END
+                WHERE user__id = $7
+                RETURNING user__id AS user_id, email, first_name, last_name, display_name, bio, locale, timezone, created_at, email_verified_at, last_login
+            ",
             body.first_name,
             body.last_name,
-            token.user_id
+            body.display_name,
+            body.bio,
+            body.locale,
+            body.timezone,
+            &token.user_id,
         )
-        .execute(&state.db)
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or(MyProblem::NotFound)?;
+
+        Ok(Json(UserProfile::from_row(row)))
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+#[api_v2_operation(
+    summary = "Change email",
+    description = "Start changing the email address of the user. A confirmation link is sent to the new address and the email address is only changed once it is followed; a link to cancel the change is sent to the current address.",
+    operation_id = "user_settings.change_email",
+    consumes = "application/json",
+    produces = "application/json",
+    tags("UserSettings")
+)]
+pub async fn change_email(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    req: HttpRequest,
+    biscuit: ReqData<Biscuit>,
+    body: Json<ChangeEmailPost>,
+) -> Result<NoContent, MyProblem> {
+    if let Err(e) = body.validate() {
+        return Err(MyProblem::Validation(e));
+    }
+
+    if let Ok(token) = authorize_only_user(
+        &biscuit,
+        Action::UserSettingsChangeEmail,
+    ) {
+        let new_email = normalize_email(&body.new_email);
+
+        let user = query!(
+            "
+                SELECT email, password, first_name, last_name, locale
+                FROM iam.user
+                WHERE user__id = $1
+            ",
+            &token.user_id,
+        )
+        .fetch_one(&state.db)
+        .await?;
+
+        if !check_password(&body.password, &user.password, token.user_id)? {
+            return Err(MyProblem::AuthInvalidPassword);
+        }
+
+        if new_email == user.email {
+            return Err(MyProblem::EmailAlreadyUsed);
+        }
+
+        let email_is_used = query_scalar!(
+            r#"
+                SELECT EXISTS(SELECT 1 FROM iam.user WHERE email = $1) AS "exists!"
+            "#,
+            &new_email,
+        )
+        .fetch_one(&state.db)
+        .await?;
+
+        if email_is_used {
+            if state.enumeration_protection {
+                // Respond as if the confirmation had been sent so that existing accounts cannot be discovered
+                debug!("User {} tried to change its email to an address that is already used", token.user_id);
+                return Ok(NoContent);
+            } else {
+                return Err(MyProblem::EmailAlreadyUsed);
+            }
+        }
+
+        let new_address = Address::from_str(&new_email).map_err(|e| {
+            // Should not happen because we checked (using a validator) that body.new_email is a well structured email address
+            error!("Error trying to parse email address: {e}");
+            MyProblem::InternalServerError
+        })?;
+        let old_address = Address::from_str(&user.email).map_err(|e| {
+            error!("Error trying to parse email address: {e}");
+            MyProblem::InternalServerError
+        })?;
+        let name = format!("{} {}", user.first_name, user.last_name);
+        let locale = Locale::negotiate(user.locale.as_deref(), &req);
+
+        let change_token = create_email_change_token(
+            &state.biscuit_private_key,
+            token.user_id,
+            &user.email,
+            &new_email,
+        )
+        .map_err(|e| {
+            error!("Error trying to create email change token: {e}");
+            MyProblem::InternalServerError
+        })?;
+        let undo_token = create_email_change_undo_token(
+            &state.biscuit_private_key,
+            token.user_id,
+            &user.email,
+            &new_email,
+        )
+        .map_err(|e| {
+            error!("Error trying to create email change undo token: {e}");
+            MyProblem::InternalServerError
+        })?;
+
+        let mut tx = state.db.begin().await?;
+
+        // Only the last requested change can be confirmed
+        query!(
+            "
+                UPDATE iam.token
+                SET expired_at = statement_timestamp()
+                WHERE user__id = $1
+                    AND type = 'email_change'
+                    AND expired_at > statement_timestamp()
+            ",
+            &token.user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        query!(
+            "
+                INSERT INTO iam.token (type, revocation_id, expired_at, user__id)
+                VALUES ('email_change', $1, $2, $3)
+            ",
+            &change_token.revocation_id,
+            change_token.expired_at,
+            &token.user_id,
+        )
+        .execute(&mut *tx)
         .await?;
 
+        query!(
+            "
+                INSERT INTO iam.token (type, revocation_id, expired_at, user__id)
+                VALUES ('email_change_undo', $1, $2, $3)
+            ",
+            &undo_token.revocation_id,
+            undo_token.expired_at,
+            &token.user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        state
+            .mailer
+            .queue_mail(
+                &mut *tx,
+                Mail::ConfirmEmailChange {
+                    url: format!(
+                        "{}confirm-email-change?token={}",
+                        state.app_url, &change_token.serialized_biscuit
+                    ),
+                },
+                Mailbox::new(Some(name.to_owned()), new_address),
+                locale,
+            )
+            .await?;
+
+        // The current address must be told about the change before it can be confirmed
+        state
+            .mailer
+            .queue_mail(
+                &mut *tx,
+                Mail::EmailChangeRequested {
+                    url: format!(
+                        "{}undo-email-change?token={}",
+                        state.app_url, &undo_token.serialized_biscuit
+                    ),
+                    new_email,
+                },
+                Mailbox::new(Some(name), old_address),
+                locale,
+            )
+            .await?;
+
+        tx.commit().await?;
+
         Ok(NoContent)
     } else {
         Err(MyProblem::Forbidden)
@@ -121,27 +411,116 @@ pub async fn change_name(
 
 #[api_v2_operation(
     summary = "Delete user",
-    description = "Delete the user and all he's information.",
+    description = "Request the deletion of the user. All sessions are revoked and the user and all their information are permanently deleted after a grace period, unless the deletion is cancelled using the link sent by email.",
     operation_id = "user_settings.delete_user",
+    consumes = "application/json",
     produces = "application/json",
     tags("UserSettings")
 )]
 pub async fn delete_user(
     state: Data<crate::State>,
     _: OaBiscuitUserAccess,
+    req: HttpRequest,
     biscuit: ReqData<Biscuit>,
+    body: Json<DeleteUserBody>,
 ) -> Result<NoContent, MyProblem> {
+    if let Err(e) = body.validate() {
+        return Err(MyProblem::Validation(e));
+    }
+
     if let Ok(token) = authorize_only_user(
         &biscuit,
         Action::UserSettingsDeleteUser,
     ) {
+        let user = query!(
+            "
+                SELECT email, password, first_name, last_name, locale
+                FROM iam.user
+                WHERE user__id = $1
+            ",
+            &token.user_id,
+        )
+        .fetch_one(&state.db)
+        .await?;
+
+        if !check_password(&body.password, &user.password, token.user_id)? {
+            return Err(MyProblem::AuthInvalidPassword);
+        }
+
+        let cancel_token = create_account_deletion_cancel_token(
+            &state.biscuit_private_key,
+            token.user_id,
+            state.account_deletion_grace_period.to_std().unwrap_or_default(),
+        )
+        .map_err(|e| {
+            error!("Error trying to create account deletion cancel token: {e}");
+            MyProblem::InternalServerError
+        })?;
+        let address = Address::from_str(&user.email).map_err(|e| {
+            error!("Error trying to parse email address: {e}");
+            MyProblem::InternalServerError
+        })?;
+
+        let mut tx = state.db.begin().await?;
+
+        query!(
+            "
+                UPDATE iam.user
+                SET deletion_requested_at = statement_timestamp()
+                WHERE user__id = $1
+                    AND deletion_requested_at IS NULL
+            ",
+            &token.user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        revoke_user_sessions(&mut tx, token.user_id, None).await?;
+
+        // Only the link of the last deletion request can cancel it
         query!(
-            "DELETE FROM iam.user WHERE user__id = $1",
-            token.user_id
+            "
+                UPDATE iam.token
+                SET expired_at = statement_timestamp()
+                WHERE user__id = $1
+                    AND type = 'account_deletion_cancel'
+                    AND expired_at > statement_timestamp()
+            ",
+            &token.user_id,
         )
-        .execute(&state.db)
+        .execute(&mut *tx)
         .await?;
 
+        query!(
+            "
+                INSERT INTO iam.token (type, revocation_id, expired_at, user__id)
+                VALUES ('account_deletion_cancel', $1, $2, $3)
+            ",
+            &cancel_token.revocation_id,
+            cancel_token.expired_at,
+            &token.user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        state
+            .mailer
+            .queue_mail(
+                &mut *tx,
+                Mail::AccountDeletionRequested {
+                    url: format!(
+                        "{}cancel-account-deletion?token={}",
+                        state.app_url, &cancel_token.serialized_biscuit
+                    ),
+                    days: state.account_deletion_grace_period.num_days(),
+                },
+                Mailbox::new(Some(format!("{} {}", user.first_name, user.last_name)), address),
+                Locale::negotiate(user.locale.as_deref(), &req),
+            )
+            .await?;
+
+        tx.commit().await?;
+
         Ok(NoContent)
     } else {
         Err(MyProblem::Forbidden)
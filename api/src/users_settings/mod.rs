@@ -1 +1,7 @@
-pub mod main;
\ No newline at end of file
+pub mod main;
+
+pub mod profile_picture;
+
+pub mod deletion;
+
+pub mod data_export;
\ No newline at end of file
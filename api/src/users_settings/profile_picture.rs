@@ -0,0 +1,430 @@
+use actix_multipart::Multipart;
+use actix_web::http::header::{HeaderValue, ACCEPT, CACHE_CONTROL, ETAG, IF_NONE_MATCH, VARY};
+use actix_web::web::{block, Path, Query, ReqData};
+use actix_web::{HttpRequest, HttpResponse};
+use biscuit_auth::Biscuit;
+use futures_util::TryStreamExt;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ExtendedColorType, ImageFormat, ImageReader, Limits, Rgb, RgbImage};
+use log::{debug, error, warn};
+use paperclip::actix::web::Data;
+use paperclip::actix::NoContent;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_scalar, PgPool};
+use std::io::Cursor;
+use strum::{AsRefStr, EnumIter, IntoEnumIterator};
+use uuid::Uuid;
+
+use crate::auth::iam::{authorize_only_user, Action};
+use crate::utils::openapi::OaBiscuitUserAccess;
+use crate::utils::problems::MyProblem;
+use crate::utils::storage::Storage;
+
+pub(crate) const MAX_FILE_SIZE: usize = 1024 * 1024 * 5;
+const MAX_IMAGE_DIMENSION: u32 = 8192;
+const ALLOWED_FORMATS: [ImageFormat; 3] = [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP];
+const JPEG_QUALITY: u8 = 85;
+
+/// Profile pictures are stored as squares of this size, and as smaller thumbnails
+pub(crate) const IMAGE_SIZE: u32 = 200;
+pub(crate) const THUMBNAIL_SIZES: [u32; 2] = [64, 32];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr, EnumIter)]
+#[strum(serialize_all = "lowercase")]
+pub(crate) enum ProfilePictureFormat {
+    Jpeg,
+    Webp,
+}
+
+impl ProfilePictureFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            ProfilePictureFormat::Jpeg => "image/jpeg",
+            ProfilePictureFormat::Webp => "image/webp",
+        }
+    }
+}
+
+fn profile_picture_sizes() -> impl Iterator<Item = u32> {
+    [IMAGE_SIZE].into_iter().chain(THUMBNAIL_SIZES)
+}
+
+/// Storage key of a profile picture
+///
+/// Each upload is stored under a new version, which the user points to once every size and format has been written.
+pub(crate) fn profile_picture_key(
+    user_id: Uuid,
+    version: Uuid,
+    size: u32,
+    format: ProfilePictureFormat,
+) -> String {
+    format!("profile-pictures/{user_id}/{version}/{size}.{}", format.as_ref())
+}
+
+// Multipart uploads are not described by paperclip, so this handler is not annotated with api_v2_operation
+pub async fn change_profile_picture(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+    mut payload: Multipart,
+) -> Result<NoContent, MyProblem> {
+    if let Ok(token) = authorize_only_user(
+        &biscuit,
+        Action::UserSettingsChangeProfilePicture,
+    ) {
+        let mut upload = Vec::new();
+        if let Some(mut field) = payload.try_next().await.map_err(|e| {
+            debug!("Error trying to read profile picture: {e}");
+            MyProblem::InvalidProfilePicture
+        })? {
+            // Stop reading as soon as the limit is reached instead of buffering the whole upload
+            while let Some(chunk) = field.try_next().await.map_err(|e| {
+                debug!("Error trying to read profile picture: {e}");
+                MyProblem::InvalidProfilePicture
+            })? {
+                if upload.len() + chunk.len() > MAX_FILE_SIZE {
+                    return Err(MyProblem::ProfilePictureTooLarge(MAX_FILE_SIZE));
+                }
+                upload.extend_from_slice(&chunk);
+            }
+        }
+
+        if upload.is_empty() {
+            debug!("No profile picture found in the request");
+            return Err(MyProblem::InvalidProfilePicture);
+        }
+
+        let user_id = token.user_id;
+        let version = Uuid::new_v4();
+        let pictures = block(move || {
+            let image = decode_profile_picture(&upload)?;
+            encode_profile_pictures(user_id, version, &image)
+        })
+        .await
+        .map_err(|e| {
+            error!("Error trying to process profile picture: {e}");
+            MyProblem::InternalServerError
+        })??;
+
+        let storage = state.storage.as_ref();
+        for (key, content, format) in pictures {
+            if let Err(e) = storage.put(&key, content, format.content_type()).await {
+                discard_profile_pictures(storage, user_id, version).await;
+                return Err(e);
+            }
+        }
+
+        // Readers switch to the new picture in every size and format at once
+        let previous_version = match set_profile_picture_version(&state.db, user_id, Some(version)).await {
+            Ok(previous_version) => previous_version,
+            Err(e) => {
+                discard_profile_pictures(storage, user_id, version).await;
+                return Err(e);
+            }
+        };
+        if let Some(previous_version) = previous_version {
+            discard_profile_pictures(storage, user_id, previous_version).await;
+        }
+
+        Ok(NoContent)
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+/// Decode an upload after checking its format from its magic bytes, whatever content type the client declared
+fn decode_profile_picture(upload: &[u8]) -> Result<DynamicImage, MyProblem> {
+    let format = image::guess_format(upload).map_err(|e| {
+        debug!("Could not guess the format of a profile picture: {e}");
+        MyProblem::InvalidProfilePicture
+    })?;
+    if !ALLOWED_FORMATS.contains(&format) {
+        debug!("Profile picture format {format:?} is not allowed");
+        return Err(MyProblem::InvalidProfilePicture);
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+
+    let mut reader = ImageReader::with_format(Cursor::new(upload), format);
+    reader.limits(limits);
+    reader.decode().map_err(|e| {
+        debug!("Could not decode profile picture: {e}");
+        MyProblem::InvalidProfilePicture
+    })
+}
+
+/// Center-crop the image to a square, then encode it in every size and every format
+///
+/// Only decoded pixels are encoded back, so EXIF and any other metadata of the upload are dropped.
+fn encode_profile_pictures(
+    user_id: Uuid,
+    version: Uuid,
+    image: &DynamicImage,
+) -> Result<Vec<(String, Vec<u8>, ProfilePictureFormat)>, MyProblem> {
+    let side = image.width().min(image.height());
+    let square = image.crop_imm(
+        (image.width() - side) / 2,
+        (image.height() - side) / 2,
+        side,
+        side,
+    );
+
+    let mut pictures = Vec::new();
+    for size in profile_picture_sizes() {
+        let resized = square.resize_exact(size, size, FilterType::Lanczos3);
+
+        for format in ProfilePictureFormat::iter() {
+            let content = match format {
+                ProfilePictureFormat::Jpeg => encode_jpeg(&resized)?,
+                ProfilePictureFormat::Webp => encode_webp(&resized)?,
+            };
+            pictures.push((profile_picture_key(user_id, version, size, format), content, format));
+        }
+    }
+
+    Ok(pictures)
+}
+
+fn encode_jpeg(image: &DynamicImage) -> Result<Vec<u8>, MyProblem> {
+    // JPEG has no alpha channel, so transparent pixels are laid over a white background
+    let rgba = image.to_rgba8();
+    let rgb = RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend = |c: u8| ((u16::from(c) * u16::from(a) + 255 * (255 - u16::from(a))) / 255) as u8;
+        Rgb([blend(r), blend(g), blend(b)])
+    });
+
+    let mut buffer = Vec::new();
+    JpegEncoder::new_with_quality(&mut buffer, JPEG_QUALITY)
+        .encode_image(&rgb)
+        .map_err(|e| {
+            error!("Error trying to encode profile picture to JPEG: {e}");
+            MyProblem::InternalServerError
+        })?;
+
+    Ok(buffer)
+}
+
+fn encode_webp(image: &DynamicImage) -> Result<Vec<u8>, MyProblem> {
+    let rgba = image.to_rgba8();
+
+    let mut buffer = Vec::new();
+    WebPEncoder::new_lossless(&mut buffer)
+        .encode(&rgba, rgba.width(), rgba.height(), ExtendedColorType::Rgba8)
+        .map_err(|e| {
+            error!("Error trying to encode profile picture to WebP: {e}");
+            MyProblem::InternalServerError
+        })?;
+
+    Ok(buffer)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfilePictureQs {
+    /// Either the default size or one of the thumbnail sizes
+    size: Option<u32>,
+}
+
+// Binary responses are not described by paperclip, so this handler is not annotated with api_v2_operation
+pub async fn get_profile_picture(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    req: HttpRequest,
+    biscuit: ReqData<Biscuit>,
+    user_id: Path<Uuid>,
+    qs: Query<ProfilePictureQs>,
+) -> Result<HttpResponse, MyProblem> {
+    if authorize_only_user(&biscuit, Action::UserSettingsGetProfilePicture).is_ok() {
+        let user_id = user_id.into_inner();
+        let size = qs.size.unwrap_or(IMAGE_SIZE);
+        if !profile_picture_sizes().any(|s| s == size) {
+            return Err(MyProblem::NotFound);
+        }
+
+        let user = query!(
+            "
+                SELECT first_name, last_name, profile_picture_version
+                FROM iam.user
+                WHERE user__id = $1
+            ",
+            &user_id,
+        )
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or(MyProblem::NotFound)?;
+
+        let accepts_webp = req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .map(|accept| accept.contains("image/webp"))
+            .unwrap_or(false);
+        let format = if accepts_webp {
+            ProfilePictureFormat::Webp
+        } else {
+            ProfilePictureFormat::Jpeg
+        };
+
+        let picture = match user.profile_picture_version {
+            Some(version) => {
+                state
+                    .storage
+                    .get(&profile_picture_key(user_id, version, size, format))
+                    .await?
+            }
+            None => None,
+        };
+        let mut res = match picture {
+            Some(picture) => etag_response(&req, &picture.content_type, picture.content),
+            None => initials_avatar(&req, user_id, &user.first_name, &user.last_name, size),
+        };
+
+        // Pictures can change at any time, so clients must revalidate them using the ETag
+        res.headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("private, no-cache"));
+        res.headers_mut()
+            .insert(VARY, HeaderValue::from_static("Accept"));
+
+        Ok(res)
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+/// Generate an SVG avatar with the initials of the user, on a background color derived from their ID
+fn initials_avatar(
+    req: &HttpRequest,
+    user_id: Uuid,
+    first_name: &str,
+    last_name: &str,
+    size: u32,
+) -> HttpResponse {
+    let initials: String = [first_name, last_name]
+        .iter()
+        .filter_map(|name| name.chars().next())
+        .flat_map(char::to_uppercase)
+        .collect();
+    let hue = user_id.as_u128() % 360;
+
+    let svg = format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{size}" height="{size}" viewBox="0 0 100 100"><rect width="100" height="100" fill="hsl({hue}, 45%, 45%)"/><text x="50" y="50" dy=".35em" fill="#ffffff" font-family="sans-serif" font-size="40" text-anchor="middle">{}</text></svg>"##,
+        escape_xml(&initials),
+    );
+
+    etag_response(req, "image/svg+xml", svg.into_bytes())
+}
+
+/// Respond with an ETag derived from the content, or with `304 Not Modified` if the client already has it
+fn etag_response(req: &HttpRequest, content_type: &str, content: Vec<u8>) -> HttpResponse {
+    let etag = format!("\"{:x}\"", Sha256::digest(&content));
+
+    let is_fresh = req
+        .headers()
+        .get(IF_NONE_MATCH)
+        .and_then(|if_none_match| if_none_match.to_str().ok())
+        .map(|if_none_match| {
+            if_none_match
+                .split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        })
+        .unwrap_or(false);
+
+    if is_fresh {
+        HttpResponse::NotModified()
+            .insert_header((ETAG, etag))
+            .finish()
+    } else {
+        HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header((ETAG, etag))
+            .body(content)
+    }
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Mounted next to the upload route, which is not described by paperclip
+pub async fn delete_profile_picture(
+    state: Data<crate::State>,
+    _: OaBiscuitUserAccess,
+    biscuit: ReqData<Biscuit>,
+) -> Result<NoContent, MyProblem> {
+    if let Ok(token) = authorize_only_user(
+        &biscuit,
+        Action::UserSettingsDeleteProfilePicture,
+    ) {
+        let previous_version = set_profile_picture_version(&state.db, token.user_id, None).await?;
+        if let Some(previous_version) = previous_version {
+            discard_profile_pictures(state.storage.as_ref(), token.user_id, previous_version).await;
+        }
+
+        Ok(NoContent)
+    } else {
+        Err(MyProblem::Forbidden)
+    }
+}
+
+/// Point the user to another version of their profile picture, and return the version they pointed to until now
+async fn set_profile_picture_version(
+    db: &PgPool,
+    user_id: Uuid,
+    version: Option<Uuid>,
+) -> Result<Option<Uuid>, MyProblem> {
+    let previous_version = query_scalar!(
+        r#"
+            UPDATE iam.user AS u
+            SET profile_picture_version = $2
+            FROM (
+                SELECT user__id, profile_picture_version
+                FROM iam.user
+                WHERE user__id = $1
+                FOR UPDATE
+            ) AS previous
+            WHERE u.user__id = previous.user__id
+            RETURNING previous.profile_picture_version AS "previous_version?"
+        "#,
+        &user_id,
+        version,
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or(MyProblem::NotFound)?;
+
+    Ok(previous_version)
+}
+
+/// Remove every size and format of a version of the profile picture of a user
+pub(crate) async fn remove_profile_pictures(
+    storage: &dyn Storage,
+    user_id: Uuid,
+    version: Uuid,
+) -> Result<(), MyProblem> {
+    for size in profile_picture_sizes() {
+        for format in ProfilePictureFormat::iter() {
+            storage
+                .delete(&profile_picture_key(user_id, version, size, format))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove a version of the profile picture that is not used anymore; files left behind are only logged
+pub(crate) async fn discard_profile_pictures(storage: &dyn Storage, user_id: Uuid, version: Uuid) {
+    if remove_profile_pictures(storage, user_id, version).await.is_err() {
+        warn!("Profile pictures of user {user_id} (version {version}) could not be removed");
+    }
+}
\ No newline at end of file
@@ -0,0 +1,43 @@
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use sqlx::types::ipnetwork::IpNetwork;
+use std::net::{IpAddr, SocketAddr};
+
+/// Information about the client that sent a request, as stored alongside sessions
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub ip: Option<IpNetwork>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    /// Extract client information from a request
+    ///
+    /// `Forwarded`/`X-Forwarded-For` headers can be forged by clients, so they are only used if `trust_proxy_headers` is set.
+    pub fn from_request(req: &HttpRequest, trust_proxy_headers: bool) -> Self {
+        let ip = if trust_proxy_headers {
+            req.connection_info()
+                .realip_remote_addr()
+                .and_then(parse_ip)
+        } else {
+            req.peer_addr().map(|addr| addr.ip())
+        };
+
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|str| str.chars().take(500).collect());
+
+        Self {
+            ip: ip.map(IpNetwork::from),
+            user_agent,
+        }
+    }
+}
+
+fn parse_ip(str: &str) -> Option<IpAddr> {
+    str.parse::<IpAddr>()
+        .ok()
+        .or_else(|| str.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
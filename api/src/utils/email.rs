@@ -0,0 +1,4 @@
+/// Normalize an email address before storing it or looking it up, so that addresses that only differ by case belong to the same user
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
@@ -0,0 +1,58 @@
+use actix_web::http::header::ACCEPT_LANGUAGE;
+use actix_web::HttpRequest;
+use strum::{AsRefStr, EnumIter, IntoEnumIterator};
+
+/// Languages in which mails are available
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, AsRefStr, EnumIter, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    Fr,
+}
+
+impl Locale {
+    /// Match a BCP 47 language tag such as `fr-CA` on its primary language subtag
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        let language = tag.trim().split(['-', '_']).next()?;
+        Self::iter().find(|locale| locale.as_ref().eq_ignore_ascii_case(language))
+    }
+
+    /// Pick the supported language with the highest weight in an `Accept-Language` header
+    pub fn from_accept_language(header: &str) -> Option<Self> {
+        let mut languages = header
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let tag = parts.next()?.trim();
+                let weight = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map(|q| q.trim().parse::<f32>().unwrap_or(0.0))
+                    .unwrap_or(1.0);
+                (weight > 0.0).then_some((tag, weight))
+            })
+            .collect::<Vec<_>>();
+        // The sort is stable, so the order of the header is kept between languages of the same weight
+        languages.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        languages.into_iter().find_map(|(tag, _)| Self::from_tag(tag))
+    }
+
+    /// Language of a user that is not making the current request: their stored locale, or English
+    pub fn from_stored(stored: Option<&str>) -> Self {
+        stored.and_then(Self::from_tag).unwrap_or_default()
+    }
+
+    /// Language of the user making a request: their stored locale, then the preferred language of the request, then English
+    pub fn negotiate(stored: Option<&str>, req: &HttpRequest) -> Self {
+        stored
+            .and_then(Self::from_tag)
+            .or_else(|| {
+                req.headers()
+                    .get(ACCEPT_LANGUAGE)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(Self::from_accept_language)
+            })
+            .unwrap_or_default()
+    }
+}
\ No newline at end of file
@@ -0,0 +1,229 @@
+use actix_web::rt::time::interval;
+use lettre::message::Mailbox;
+use log::{error, info, warn};
+use sqlx::{query, query_as, Acquire, PgPool, Postgres};
+use std::str::FromStr;
+use std::time::Duration;
+use strum::AsRefStr;
+use uuid::Uuid;
+
+use crate::utils::mail_transport::DeliveryError;
+use crate::utils::mailer::{Mailer, RenderedMail};
+use crate::utils::problems::MyProblem;
+
+/// Number of mails claimed at once by a worker
+const DELIVERY_BATCH_SIZE: i64 = 10;
+/// Claimed mails are not picked up by other workers during this time, even if the worker that claimed them crashed
+const DELIVERY_LEASE_IN_S: f64 = 300.0;
+/// Delay before retrying a failed delivery; it doubles after each attempt
+const RETRY_BASE_DELAY_IN_S: f64 = 30.0;
+const RETRY_MAX_DELAY_IN_S: f64 = 3600.0;
+/// Number of days during which sent mails are kept in the outbox
+const SENT_MAIL_RETENTION_IN_DAYS: i32 = 30;
+
+/// Delivery status of a mail of the outbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum MailStatus {
+    /// Waiting for its first or next delivery attempt
+    Pending,
+    Sent,
+    /// Delivery failed permanently or too many times; the mail will not be retried
+    Dead,
+}
+
+struct OutboxMail {
+    mail_outbox_id: Uuid,
+    kind: String,
+    recipient: String,
+    subject: String,
+    html_body: String,
+    text_body: String,
+    attempts: i32,
+}
+
+pub(crate) async fn enqueue_mail<'a, A: Acquire<'a, Database = Postgres>>(
+    db: A,
+    kind: &str,
+    recipient: &Mailbox,
+    rendered: &RenderedMail,
+) -> Result<(), MyProblem> {
+    let mut db = db.acquire().await?;
+
+    query!(
+        "
+            INSERT INTO infrastructure.mail_outbox (kind, recipient, subject, html_body, text_body, status)
+            VALUES ($1, $2, $3, $4, $5, $6)
+        ",
+        kind,
+        recipient.to_string(),
+        &rendered.subject,
+        &rendered.html,
+        &rendered.text,
+        MailStatus::Pending.as_ref(),
+    )
+    .execute(&mut *db)
+    .await?;
+
+    Ok(())
+}
+
+/// Deliver the mails of the outbox every `every` in the background
+pub fn spawn_delivery_worker(db: PgPool, mailer: Mailer, every: Duration, max_attempts: u32) {
+    actix_web::rt::spawn(async move {
+        let mut ticker = interval(every);
+        loop {
+            ticker.tick().await;
+
+            // Keep going while there is a backlog instead of waiting for the next tick
+            loop {
+                match deliver_pending_mails(&db, &mailer, max_attempts).await {
+                    Ok(count) if count < DELIVERY_BATCH_SIZE as usize => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("Error trying to deliver mails: {e}");
+                        break;
+                    }
+                }
+            }
+
+            if let Err(e) = purge_sent_mails(&db).await {
+                error!("Error trying to purge sent mails: {e}");
+            }
+        }
+    });
+}
+
+/// Claim a batch of due mails and try to deliver them; returns the number of claimed mails
+async fn deliver_pending_mails(
+    db: &PgPool,
+    mailer: &Mailer,
+    max_attempts: u32,
+) -> Result<usize, MyProblem> {
+    let mails = query_as!(
+        OutboxMail,
+        r#"
+            UPDATE infrastructure.mail_outbox
+            SET next_attempt_at = statement_timestamp() + make_interval(secs => $3)
+            WHERE mail_outbox__id IN (
+                SELECT mail_outbox__id
+                FROM infrastructure.mail_outbox
+                WHERE status = $1 AND next_attempt_at <= statement_timestamp()
+                ORDER BY next_attempt_at
+                LIMIT $2
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING mail_outbox__id AS mail_outbox_id, kind, recipient, subject, html_body AS "html_body!", text_body AS "text_body!", attempts
+        "#,
+        MailStatus::Pending.as_ref(),
+        DELIVERY_BATCH_SIZE,
+        DELIVERY_LEASE_IN_S,
+    )
+    .fetch_all(db)
+    .await?;
+
+    let count = mails.len();
+    for mail in mails {
+        deliver_mail(db, mailer, max_attempts, mail).await?;
+    }
+
+    Ok(count)
+}
+
+async fn deliver_mail(
+    db: &PgPool,
+    mailer: &Mailer,
+    max_attempts: u32,
+    mail: OutboxMail,
+) -> Result<(), MyProblem> {
+    let attempts = mail.attempts + 1;
+    let result = match Mailbox::from_str(&mail.recipient) {
+        Ok(recipient) => {
+            mailer
+                .deliver(
+                    recipient,
+                    RenderedMail {
+                        subject: mail.subject,
+                        html: mail.html_body,
+                        text: mail.text_body,
+                    },
+                )
+                .await
+        }
+        Err(e) => Err(DeliveryError::Permanent(format!("invalid recipient: {e}"))),
+    };
+
+    match result {
+        Ok(()) => {
+            query!(
+                "
+                    UPDATE infrastructure.mail_outbox
+                    SET status = $1, sent_at = statement_timestamp(), attempts = $2, last_error = NULL, html_body = NULL, text_body = NULL
+                    WHERE mail_outbox__id = $3
+                ",
+                MailStatus::Sent.as_ref(),
+                attempts,
+                &mail.mail_outbox_id,
+            )
+            .execute(db)
+            .await?;
+            info!("Sent {} mail {}", mail.kind, mail.mail_outbox_id);
+        }
+        Err(e) if matches!(e, DeliveryError::Permanent(_)) || attempts >= max_attempts as i32 => {
+            query!(
+                "
+                    UPDATE infrastructure.mail_outbox
+                    SET status = $1, attempts = $2, last_error = $3
+                    WHERE mail_outbox__id = $4
+                ",
+                MailStatus::Dead.as_ref(),
+                attempts,
+                e.to_string(),
+                &mail.mail_outbox_id,
+            )
+            .execute(db)
+            .await?;
+            error!(
+                "Giving up on {} mail {} after {attempts} attempt(s): {e}",
+                mail.kind, mail.mail_outbox_id
+            );
+        }
+        Err(e) => {
+            let delay = (RETRY_BASE_DELAY_IN_S * 2f64.powi(attempts - 1)).min(RETRY_MAX_DELAY_IN_S);
+            query!(
+                "
+                    UPDATE infrastructure.mail_outbox
+                    SET attempts = $1, last_error = $2, next_attempt_at = statement_timestamp() + make_interval(secs => $3)
+                    WHERE mail_outbox__id = $4
+                ",
+                attempts,
+                e.to_string(),
+                delay,
+                &mail.mail_outbox_id,
+            )
+            .execute(db)
+            .await?;
+            warn!(
+                "Could not send {} mail {} (attempt {attempts}), retrying in {delay} seconds: {e}",
+                mail.kind, mail.mail_outbox_id
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn purge_sent_mails(db: &PgPool) -> Result<(), MyProblem> {
+    query!(
+        "
+            DELETE FROM infrastructure.mail_outbox
+            WHERE status = $1 AND sent_at < statement_timestamp() - make_interval(days => $2)
+        ",
+        MailStatus::Sent.as_ref(),
+        SENT_MAIL_RETENTION_IN_DAYS,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
\ No newline at end of file
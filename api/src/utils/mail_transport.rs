@@ -0,0 +1,181 @@
+use clap::ValueEnum;
+use futures_util::future::{ready, BoxFuture};
+use lettre::transport::smtp::Error as SmtpError;
+use lettre::{
+    AsyncFileTransport, AsyncSendmailTransport, AsyncSmtpTransport, AsyncTransport, Message,
+    Tokio1Executor,
+};
+use log::{info, warn};
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How mails are delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MailTransportKind {
+    /// To an SMTP server
+    Smtp,
+    /// Using the local `sendmail` command
+    Sendmail,
+    /// As `.eml` files written to a directory of the local filesystem
+    File,
+    /// Printed to the standard output
+    Stdout,
+    /// Kept in the memory of the current instance; mails are lost on restart
+    Memory,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryError {
+    /// Retrying will not help, for example because the recipient was rejected
+    Permanent(String),
+    Transient(String),
+}
+
+impl std::fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryError::Permanent(e) => write!(f, "permanent failure: {e}"),
+            DeliveryError::Transient(e) => write!(f, "transient failure: {e}"),
+        }
+    }
+}
+
+pub trait MailTransport: Debug + Send + Sync {
+    fn send(&self, message: Message) -> BoxFuture<'_, Result<(), DeliveryError>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct SmtpMailTransport {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailTransport {
+    pub async fn new(connection_url: &str, timeout: Duration) -> Result<Self, SmtpError> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(connection_url)?
+            .timeout(Some(timeout))
+            .build();
+
+        let test = transport.test_connection().await;
+        match test {
+            Ok(true) => info!("SMTP server is up"),
+            Ok(false) => warn!("SMTP server connection test failed"),
+            Err(e) => warn!("SMTP server connection test failed: {e}"),
+        }
+
+        Ok(Self { transport })
+    }
+}
+
+impl MailTransport for SmtpMailTransport {
+    fn send(&self, message: Message) -> BoxFuture<'_, Result<(), DeliveryError>> {
+        Box::pin(async move {
+            self.transport.send(message).await.map_err(|e| {
+                if e.is_permanent() {
+                    DeliveryError::Permanent(e.to_string())
+                } else {
+                    DeliveryError::Transient(e.to_string())
+                }
+            })?;
+
+            Ok(())
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct SendmailMailTransport {
+    transport: AsyncSendmailTransport<Tokio1Executor>,
+}
+
+impl SendmailMailTransport {
+    /// Use `command` instead of the `sendmail` found in `PATH`, if provided
+    pub fn new(command: Option<&str>) -> Self {
+        let transport = match command {
+            Some(command) => AsyncSendmailTransport::<Tokio1Executor>::new_with_command(command),
+            None => AsyncSendmailTransport::<Tokio1Executor>::new(),
+        };
+
+        Self { transport }
+    }
+}
+
+impl MailTransport for SendmailMailTransport {
+    fn send(&self, message: Message) -> BoxFuture<'_, Result<(), DeliveryError>> {
+        Box::pin(async move {
+            self.transport
+                .send(message)
+                .await
+                .map_err(|e| DeliveryError::Transient(e.to_string()))
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileMailTransport {
+    transport: AsyncFileTransport<Tokio1Executor>,
+}
+
+impl FileMailTransport {
+    pub fn new(directory: impl AsRef<Path>) -> std::io::Result<Self> {
+        std::fs::create_dir_all(directory.as_ref())?;
+
+        Ok(Self {
+            transport: AsyncFileTransport::<Tokio1Executor>::new(directory.as_ref()),
+        })
+    }
+}
+
+impl MailTransport for FileMailTransport {
+    fn send(&self, message: Message) -> BoxFuture<'_, Result<(), DeliveryError>> {
+        Box::pin(async move {
+            let id = self
+                .transport
+                .send(message)
+                .await
+                .map_err(|e| DeliveryError::Transient(e.to_string()))?;
+            info!("Mail written to {id}.eml");
+
+            Ok(())
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct StdoutMailTransport;
+
+impl MailTransport for StdoutMailTransport {
+    fn send(&self, message: Message) -> BoxFuture<'_, Result<(), DeliveryError>> {
+        println!("{}", String::from_utf8_lossy(&message.formatted()));
+
+        Box::pin(ready(Ok(())))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryMailTransport {
+    messages: Mutex<Vec<Message>>,
+}
+
+impl MemoryMailTransport {
+    fn messages(&self) -> std::sync::MutexGuard<'_, Vec<Message>> {
+        self.messages
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Mails sent so far, in order
+    #[cfg_attr(not(test), allow(dead_code))] // Only read by tests
+    pub fn sent(&self) -> Vec<Message> {
+        self.messages().clone()
+    }
+}
+
+impl MailTransport for MemoryMailTransport {
+    fn send(&self, message: Message) -> BoxFuture<'_, Result<(), DeliveryError>> {
+        self.messages().push(message);
+
+        Box::pin(ready(Ok(())))
+    }
+}
\ No newline at end of file
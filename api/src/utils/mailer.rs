@@ -1,97 +1,283 @@
 use html2text::from_read;
 use lettre::message::{Mailbox, MultiPart};
-use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
-use log::{info, warn};
+use lettre::{Address, Message};
+use minijinja::{AutoEscape, Environment, UndefinedBehavior};
+use mrml::prelude::parser::memory_loader::MemoryIncludeLoader;
+use mrml::prelude::parser::ParserOptions;
+use serde::Serialize;
+use sqlx::{Acquire, Postgres};
 use std::string::String;
-use std::time::Duration;
+use std::sync::Arc;
+use strum::{AsRefStr, EnumIter, IntoEnumIterator};
 use url::Url;
 
+use crate::utils::locale::Locale;
+use crate::utils::mail_outbox::enqueue_mail;
+use crate::utils::mail_transport::{DeliveryError, MailTransport};
 use crate::utils::problems::MyProblem;
 
 #[derive(Debug, Clone)]
 pub struct Mailer {
-    transport: AsyncSmtpTransport<Tokio1Executor>,
+    transport: Arc<dyn MailTransport>,
     sender: Mailbox,
     logo_url: Url,
+    /// HTML compiled from the MJML templates at startup, named `{locale}/{mail}`
+    templates: Arc<Environment<'static>>,
 }
 
+/// Fields of each variant are the variables of its template
+#[derive(Debug, Clone, AsRefStr, EnumIter, Serialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(untagged)]
 pub enum Mail {
     VerifyUserEmail { url: String },
     ResetPassword { url: String },
+    AccountLocked { url: String, duration: u64 },
+    RegistrationAttempt { url: String },
+    ConfirmEmailChange { url: String },
+    EmailChangeRequested { url: String, new_email: String },
+    AccountDeletionRequested { url: String, days: i64 },
+    DataExportReady { url: String, days: i64 },
 }
 
-impl Mail {
-    pub fn template(&self) -> &'static str {
-        match self {
-            Mail::VerifyUserEmail { .. } => include_str!("../mail_templates/verify_user_email.mjml"),
-            Mail::ResetPassword { .. } => include_str!("../mail_templates/reset_password.mjml"),
+/// Partials shared by all templates through `<mj-include path="partials/..." />`
+const PARTIALS: [(&str, &str); 4] = [
+    (
+        "partials/attributes.mjml",
+        include_str!("../mail_templates/partials/attributes.mjml"),
+    ),
+    ("partials/style.mjml", include_str!("../mail_templates/partials/style.mjml")),
+    ("partials/logo.mjml", include_str!("../mail_templates/partials/logo.mjml")),
+    ("partials/link.mjml", include_str!("../mail_templates/partials/link.mjml")),
+];
+
+#[derive(Debug, Serialize)]
+struct MailContext<'a> {
+    logo_url: &'a str,
+    subject: &'a str,
+    #[serde(flatten)]
+    mail: &'a Mail,
+}
+
+/// Template of a mail in a locale directory of `mail_templates`; a missing template fails the build
+macro_rules! localized_template {
+    ($mail:expr, $locale:literal) => {
+        match $mail {
+            Mail::VerifyUserEmail { .. } => include_str!(concat!("../mail_templates/", $locale, "/verify_user_email.mjml")),
+            Mail::ResetPassword { .. } => include_str!(concat!("../mail_templates/", $locale, "/reset_password.mjml")),
+            Mail::AccountLocked { .. } => include_str!(concat!("../mail_templates/", $locale, "/account_locked.mjml")),
+            Mail::RegistrationAttempt { .. } => include_str!(concat!("../mail_templates/", $locale, "/registration_attempt.mjml")),
+            Mail::ConfirmEmailChange { .. } => include_str!(concat!("../mail_templates/", $locale, "/confirm_email_change.mjml")),
+            Mail::EmailChangeRequested { .. } => include_str!(concat!("../mail_templates/", $locale, "/email_change_requested.mjml")),
+            Mail::AccountDeletionRequested { .. } => include_str!(concat!("../mail_templates/", $locale, "/account_deletion_requested.mjml")),
+            Mail::DataExportReady { .. } => include_str!(concat!("../mail_templates/", $locale, "/data_export_ready.mjml")),
         }
-    }
+    };
+}
 
-    pub fn subject(&self) -> String {
-        match self {
-            Mail::VerifyUserEmail { .. } => "Please verify your email address".to_owned(),
-            Mail::ResetPassword { .. } => "Reset your password".to_owned(),
+impl Mail {
+    pub fn template(&self, locale: Locale) -> &'static str {
+        match locale {
+            Locale::En => localized_template!(self, "en"),
+            Locale::Fr => localized_template!(self, "fr"),
         }
     }
 
-    pub fn variables(&self) -> Vec<(String, String)> {
-        match self {
-            Mail::VerifyUserEmail { url } => vec![("url".to_owned(), url.to_owned())],
-            Mail::ResetPassword { url } => vec![("url".to_owned(), url.to_owned())],
+    pub fn subject(&self, locale: Locale) -> String {
+        match locale {
+            Locale::En => match self {
+                Mail::VerifyUserEmail { .. } => "Please verify your email address".to_owned(),
+                Mail::ResetPassword { .. } => "Reset your password".to_owned(),
+                Mail::AccountLocked { .. } => "Your account has been locked".to_owned(),
+                Mail::RegistrationAttempt { .. } => "Someone tried to create an account with your email address".to_owned(),
+                Mail::ConfirmEmailChange { .. } => "Please confirm your new email address".to_owned(),
+                Mail::EmailChangeRequested { .. } => "Your email address is being changed".to_owned(),
+                Mail::AccountDeletionRequested { .. } => "Your account will be deleted".to_owned(),
+                Mail::DataExportReady { .. } => "Your personal data export is ready".to_owned(),
+            },
+            Locale::Fr => match self {
+                Mail::VerifyUserEmail { .. } => "Veuillez vérifier votre adresse email".to_owned(),
+                Mail::ResetPassword { .. } => "Réinitialisez votre mot de passe".to_owned(),
+                Mail::AccountLocked { .. } => "Votre compte a été bloqué".to_owned(),
+                Mail::RegistrationAttempt { .. } => "Quelqu'un a essayé de créer un compte avec votre adresse email".to_owned(),
+                Mail::ConfirmEmailChange { .. } => "Veuillez confirmer votre nouvelle adresse email".to_owned(),
+                Mail::EmailChangeRequested { .. } => "Votre adresse email est en cours de modification".to_owned(),
+                Mail::AccountDeletionRequested { .. } => "Votre compte va être supprimé".to_owned(),
+                Mail::DataExportReady { .. } => "L'export de vos données personnelles est prêt".to_owned(),
+            },
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedMail {
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+}
+
 impl Mailer {
-    pub async fn new(
-        smtp_connection_url: &str,
-        smtp_timeout: Duration,
+    pub fn new(
+        transport: Arc<dyn MailTransport>,
         sender_name: String,
         sender_address: Address,
         logo_url: Url,
-    ) -> Result<Mailer, lettre::transport::smtp::Error> {
-        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(smtp_connection_url)?
-            .timeout(Some(smtp_timeout))
-            .build();
+    ) -> Result<Mailer, String> {
         let sender = Mailbox::new(Some(sender_name), sender_address);
 
-        let test = transport.test_connection().await;
-        match test {
-            Ok(true) => info!("SMTP server is up"),
-            Ok(false) => warn!("SMTP server connection test failed"),
-            Err(e) => warn!("SMTP server connection test failed: {e}"),
+        let mut templates = Environment::new();
+        // Variables are escaped so that user input such as an email address cannot inject markup
+        templates.set_auto_escape_callback(|_| AutoEscape::Html);
+        templates.set_undefined_behavior(UndefinedBehavior::Strict);
+
+        let options = ParserOptions {
+            include_loader: Box::new(MemoryIncludeLoader::from(PARTIALS.to_vec())),
+        };
+        for locale in Locale::iter() {
+            for mail in Mail::iter() {
+                let name = template_name(&mail, locale);
+                let html = mrml::parse_with_options(mail.template(locale), &options)
+                    .map_err(|e| format!("Template {name} is invalid: {e}"))?
+                    .render(&Default::default())
+                    .map_err(|e| format!("Template {name} could not be rendered: {e}"))?;
+                templates
+                    .add_template_owned(name.clone(), html)
+                    .map_err(|e| format!("Template {name} is invalid: {e}"))?;
+            }
         }
 
-        Ok(Mailer {
+        let mailer = Mailer {
             transport,
             sender,
             logo_url,
-        })
-    }
+            templates: Arc::new(templates),
+        };
 
-    pub async fn send_mail(&self, mail: Mail, recipient: Mailbox) -> Result<(), MyProblem> {
-        let template = mail.template();
-        let mut mjml = template.to_owned();
-        for (key, value) in mail.variables() {
-            mjml = mjml.replace(&format!("{{ ${key} }}"), &value);
+        // Render every mail once, so that missing variables are noticed at startup rather than when sending
+        for locale in Locale::iter() {
+            for mail in Mail::iter() {
+                mailer.render(&mail, locale).map_err(|_| {
+                    format!("Template {} could not be rendered", template_name(&mail, locale))
+                })?;
+            }
         }
 
-        // Replace the logo_url variable with the actual logo_url value if { $logo_url } is present in the template
-        mjml = mjml.replace("{ $logo_url }", self.logo_url.as_str());
+        Ok(mailer)
+    }
+
+    /// Render a mail and add it to the outbox; it is delivered in the background once the transaction is committed
+    pub async fn queue_mail<'a, A: Acquire<'a, Database = Postgres>>(
+        &self,
+        db: A,
+        mail: Mail,
+        recipient: Mailbox,
+        locale: Locale,
+    ) -> Result<(), MyProblem> {
+        let rendered = self.render(&mail, locale)?;
+        enqueue_mail(db, mail.as_ref(), &recipient, &rendered).await
+    }
 
-        let parsed = mrml::parse(mjml)?;
-        let rendered = parsed.render(&Default::default())?;
+    fn render(&self, mail: &Mail, locale: Locale) -> Result<RenderedMail, MyProblem> {
+        let subject = mail.subject(locale);
+        let html = self
+            .templates
+            .get_template(&template_name(mail, locale))?
+            .render(MailContext {
+                logo_url: self.logo_url.as_str(),
+                subject: &subject,
+                mail,
+            })?;
 
-        let text_mail = from_read(rendered.as_bytes(), 80);
+        let text = from_read(html.as_bytes(), 80);
 
+        Ok(RenderedMail {
+            subject,
+            html,
+            text,
+        })
+    }
+
+    /// Send a rendered mail right away
+    pub(crate) async fn deliver(
+        &self,
+        recipient: Mailbox,
+        rendered: RenderedMail,
+    ) -> Result<(), DeliveryError> {
         let email = Message::builder()
             .from(self.sender.to_owned())
             .to(recipient)
-            .subject(mail.subject())
-            .multipart(MultiPart::alternative_plain_html(text_mail, rendered))?;
+            .subject(rendered.subject)
+            .multipart(MultiPart::alternative_plain_html(rendered.text, rendered.html))
+            .map_err(|e| DeliveryError::Permanent(e.to_string()))?;
 
-        self.transport.send(email).await?;
-        Ok(())
+        self.transport.send(email).await
     }
 }
+
+fn template_name(mail: &Mail, locale: Locale) -> String {
+    format!("{locale}/{}", mail.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::utils::mail_transport::MemoryMailTransport;
+
+    fn mailer(transport: Arc<MemoryMailTransport>) -> Mailer {
+        Mailer::new(
+            transport,
+            "Tauri Template".to_owned(),
+            Address::from_str("noreply@example.com").unwrap(),
+            Url::parse("https://example.com/logo.png").unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn delivered_mails_are_recorded_by_the_memory_transport() {
+        let transport = Arc::new(MemoryMailTransport::default());
+        let mailer = mailer(transport.clone());
+
+        let rendered = mailer
+            .render(
+                &Mail::ResetPassword {
+                    url: "https://example.com/reset-password?token=abc".to_owned(),
+                },
+                Locale::En,
+            )
+            .unwrap();
+        mailer
+            .deliver(
+                Mailbox::new(None, Address::from_str("user@example.com").unwrap()),
+                rendered,
+            )
+            .await
+            .unwrap();
+
+        let sent = transport.sent();
+        assert_eq!(sent.len(), 1);
+        let formatted = String::from_utf8_lossy(&sent[0].formatted()).into_owned();
+        assert!(formatted.contains("To: user@example.com"));
+        assert!(formatted.contains("Subject: Reset your password"));
+    }
+
+    #[test]
+    fn variables_are_html_escaped() {
+        let mailer = mailer(Arc::new(MemoryMailTransport::default()));
+
+        let rendered = mailer
+            .render(
+                &Mail::EmailChangeRequested {
+                    url: "https://example.com/undo-email-change?token=abc".to_owned(),
+                    new_email: "<b>attacker</b>@example.com".to_owned(),
+                },
+                Locale::En,
+            )
+            .unwrap();
+
+        assert!(!rendered.html.contains("<b>attacker</b>"));
+        assert!(rendered.html.contains("&lt;b&gt;attacker&lt;&#x2f;b&gt;@example.com"));
+    }
+}
\ No newline at end of file
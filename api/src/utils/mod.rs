@@ -2,4 +2,21 @@ pub mod problems;
 
 pub mod mailer;
 
-pub mod openapi;
\ No newline at end of file
+pub mod openapi;
+
+pub mod client_info;
+
+pub mod rate_limit;
+
+pub mod email;
+
+pub mod storage;
+
+pub mod mail_outbox;
+
+pub mod mail_transport;
+
+pub mod locale;
+
+#[cfg(test)]
+pub mod testing;
\ No newline at end of file
@@ -6,50 +6,124 @@ use http_api_problem::{HttpApiProblem, PROBLEM_JSON_MEDIA_TYPE};
 use log::{error, warn};
 use paperclip::actix::api_v2_errors;
 use serde_json::{to_value, Value};
-use sqlx::{postgres::PgDatabaseError, Error};
+use sqlx::Error;
 use strum::EnumIter;
 
 
-#[api_v2_errors(code = 403, code = 500, code = 400, code = 404, code = 409)]
+#[api_v2_errors(code = 403, code = 500, code = 400, code = 401, code = 404, code = 409, code = 413, code = 415, code = 422, code = 429, code = 503)]
 #[derive(Debug, Clone, EnumIter, strum::Display)]
 pub enum MyProblem {
     // Functionnal errors
     PasswordTooShort(u8),
     EmailNotVerified,
+    EmailAlreadyUsed,
+    ProfilePictureTooLarge(usize),
+    InvalidProfilePicture,
 
     // Auth errors
     AuthFailedLogin,
+    AuthInvalidPassword,
     AuthFailedRefresh,
     AuthInvalidBiscuit,
+    AuthStaleBiscuit,
     AuthBiscuitLookupError,
     AuthInvalidAuthorizationHeader,
     AuthNoAuthorizationHeader,
     AuthEmailExpired,
+    AuthAccountDisabled,
+    AuthAccountPendingDeletion,
+    AuthMfaRequired(String),
+    AuthInvalidMfaCode,
+    AuthTotpAlreadyEnabled,
+    AuthWebauthnFailed,
+    AuthTooManyAttempts(u64),
 
     // Generics errors
+    TooManyRequests(u64),
     Validation(validator::ValidationErrors),
     NotFound,
+    Conflict,
+    UnprocessableContent,
+    ServiceUnavailable(u64),
     InternalServerError,
     Forbidden,
 }
 
+// SQLSTATE codes (see https://www.postgresql.org/docs/current/errcodes-appendix.html)
+const SQLSTATE_SERIALIZATION_FAILURE: &str = "40001";
+const SQLSTATE_DEADLOCK_DETECTED: &str = "40P01";
+const SQLSTATE_TOO_MANY_CONNECTIONS: &str = "53300";
+const SQLSTATE_CANNOT_CONNECT_NOW: &str = "57P03";
+
+/// Seconds after which clients can retry a transaction that failed because of a concurrent one
+const RETRY_AFTER_CONCURRENT_TRANSACTION: u64 = 1;
+/// Seconds after which clients can retry a request that failed because the database was not available
+const RETRY_AFTER_UNAVAILABLE_DATABASE: u64 = 5;
+
+/// Problem returned when a specific constraint is violated
+///
+/// Only constraints that can be violated because of the request are listed here.
+/// Other violations come from server bugs and are reported as internal server errors.
+fn constraint_problem(constraint: &str) -> Option<MyProblem> {
+    match constraint {
+        "user_email_key" => Some(MyProblem::EmailAlreadyUsed),
+        // The account was deleted while the request was processed
+        "data_export_user__id_fk"
+        | "recovery_code_user__id_fk"
+        | "webauthn_challenge_user__id_fk"
+        | "webauthn_credential_user__id_fk" => Some(MyProblem::Conflict),
+        "user_bio_chk" | "user_display_name_chk" | "user_locale_chk" | "user_timezone_chk" => {
+            Some(MyProblem::UnprocessableContent)
+        }
+        _ => None,
+    }
+}
+
+/// Problem returned for a transient database error, depending on its SQLSTATE code
+fn sqlstate_problem(code: &str) -> Option<MyProblem> {
+    match code {
+        SQLSTATE_SERIALIZATION_FAILURE | SQLSTATE_DEADLOCK_DETECTED => Some(
+            MyProblem::ServiceUnavailable(RETRY_AFTER_CONCURRENT_TRANSACTION),
+        ),
+        SQLSTATE_TOO_MANY_CONNECTIONS | SQLSTATE_CANNOT_CONNECT_NOW => Some(
+            MyProblem::ServiceUnavailable(RETRY_AFTER_UNAVAILABLE_DATABASE),
+        ),
+        _ => None,
+    }
+}
+
 impl From<sqlx::Error> for MyProblem {
     fn from(e: Error) -> Self {
         match e {
             Error::RowNotFound => MyProblem::NotFound,
             Error::Database(ex) => {
-                // Goal map Box<dyn DatabaseError> to PgDatabaseError
-                let pg_error: &PgDatabaseError = ex.try_downcast_ref::<PgDatabaseError>().unwrap();
-
-                //let pg_error: PgDatabaseError = ex.into();
+                let problem = ex
+                    .constraint()
+                    .and_then(constraint_problem)
+                    .or_else(|| ex.code().and_then(|code| sqlstate_problem(&code)));
 
-                match pg_error.constraint() {
-                    _ => {
-                        error!("Database error: {}", &pg_error);
+                match problem {
+                    Some(problem) => {
+                        warn!(
+                            "Database error mapped to {problem} (constraint: {}): {ex}",
+                            ex.constraint().unwrap_or("none"),
+                        );
+                        problem
+                    }
+                    None => {
+                        error!("Database error: {ex}");
                         MyProblem::InternalServerError
                     }
                 }
             }
+            Error::PoolTimedOut => {
+                warn!("Timed out while waiting for a database connection");
+                MyProblem::ServiceUnavailable(RETRY_AFTER_UNAVAILABLE_DATABASE)
+            }
+            Error::Io(err) => {
+                error!("Could not communicate with the database: {err}");
+                MyProblem::ServiceUnavailable(RETRY_AFTER_UNAVAILABLE_DATABASE)
+            }
             err => {
                 error!("{}", &err);
                 MyProblem::InternalServerError
@@ -72,15 +146,8 @@ impl From<lettre::transport::smtp::Error> for MyProblem {
     }
 }
 
-impl From<mrml::prelude::parser::Error> for MyProblem {
-    fn from(err: mrml::prelude::parser::Error) -> MyProblem {
-        warn!("{err}");
-        MyProblem::InternalServerError
-    }
-}
-
-impl From<mrml::prelude::render::Error> for MyProblem {
-    fn from(err: mrml::prelude::render::Error) -> MyProblem {
+impl From<minijinja::Error> for MyProblem {
+    fn from(err: minijinja::Error) -> MyProblem {
         warn!("{err}");
         MyProblem::InternalServerError
     }
@@ -89,14 +156,21 @@ impl From<mrml::prelude::render::Error> for MyProblem {
 impl From<MyProblem> for HttpApiProblem {
     fn from(my_problem: MyProblem) -> Self {
         let problem: Problem = my_problem.to_owned().into();
-        HttpApiProblem::new(problem.status)
+        let http_problem = HttpApiProblem::new(problem.status)
             .type_url(format!(
                 "https://hook0.com/documentation/errors/{my_problem}",
             )) // rely on Display trait of MyProblem
             .value("id".to_owned(), &my_problem.to_string()) // also rely on Display trait of MyProblem
             .value("validation".to_owned(), &problem.validation)
             .title(problem.title)
-            .detail(problem.detail)
+            .detail(problem.detail);
+
+        match my_problem {
+            MyProblem::AuthMfaRequired(mfa_token) => {
+                http_problem.value("mfa_token".to_owned(), &mfa_token)
+            }
+            _ => http_problem,
+        }
     }
 }
 
@@ -117,12 +191,20 @@ impl ResponseError for MyProblem {
 
         let json = problem.json_bytes();
 
-        actix_web::HttpResponse::build(actix_status)
-            .append_header((
-                actix_web::http::header::CONTENT_TYPE,
-                PROBLEM_JSON_MEDIA_TYPE,
-            ))
-            .body(json)
+        let mut res = actix_web::HttpResponse::build(actix_status);
+        res.append_header((
+            actix_web::http::header::CONTENT_TYPE,
+            PROBLEM_JSON_MEDIA_TYPE,
+        ));
+
+        if let MyProblem::AuthTooManyAttempts(retry_after)
+        | MyProblem::TooManyRequests(retry_after)
+        | MyProblem::ServiceUnavailable(retry_after) = self
+        {
+            res.append_header((actix_web::http::header::RETRY_AFTER, retry_after.to_string()));
+        }
+
+        res.body(json)
     }
 }
 
@@ -153,6 +235,27 @@ impl From<MyProblem> for Problem {
                 validation: None,
                 status: StatusCode::FORBIDDEN,
             },
+            MyProblem::EmailAlreadyUsed => Problem {
+                id: MyProblem::EmailAlreadyUsed,
+                title: "Email address already used",
+                detail: "Another account already uses this email address.".into(),
+                validation: None,
+                status: StatusCode::CONFLICT,
+            },
+            MyProblem::ProfilePictureTooLarge(max_size) => Problem {
+                id: MyProblem::ProfilePictureTooLarge(max_size),
+                title: "Profile picture is too large",
+                detail: format!("The profile picture must not be larger than {} MiB.", max_size / 1024 / 1024).into(),
+                validation: None,
+                status: StatusCode::PAYLOAD_TOO_LARGE,
+            },
+            MyProblem::InvalidProfilePicture => Problem {
+                id: MyProblem::InvalidProfilePicture,
+                title: "Invalid profile picture",
+                detail: "The profile picture must be a JPEG, PNG or WebP image.".into(),
+                validation: None,
+                status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            },
 
 
             // Auth errors
@@ -163,6 +266,13 @@ impl From<MyProblem> for Problem {
                 validation: None,
                 status: StatusCode::FORBIDDEN,
             },
+            MyProblem::AuthInvalidPassword => Problem {
+                id: MyProblem::AuthInvalidPassword,
+                title: "Invalid password",
+                detail: "The provided password is not the current password of the account.".into(),
+                validation: None,
+                status: StatusCode::FORBIDDEN,
+            },
             MyProblem::AuthFailedRefresh => Problem {
                 id: MyProblem::AuthFailedRefresh,
                 title: "Refreshing access token failed",
@@ -177,6 +287,13 @@ impl From<MyProblem> for Problem {
                 validation: None,
                 status: StatusCode::FORBIDDEN,
             },
+            MyProblem::AuthStaleBiscuit => Problem {
+                id: MyProblem::AuthStaleBiscuit,
+                title: "Stale biscuit",
+                detail: "The account was modified after the provided access token was created. Use your refresh token to get a new access token.".into(),
+                validation: None,
+                status: StatusCode::UNAUTHORIZED,
+            },
             MyProblem::AuthBiscuitLookupError => Problem {
                 id: MyProblem::AuthBiscuitLookupError,
                 title: "Could not check database to verify if the provided Biscuit was revoked",
@@ -207,10 +324,66 @@ impl From<MyProblem> for Problem {
                     status: StatusCode::UNAUTHORIZED,
                 }
             },
+            MyProblem::AuthAccountDisabled => Problem {
+                id: MyProblem::AuthAccountDisabled,
+                title: "Account disabled",
+                detail: "This account has been disabled by an administrator. Please contact support.".into(),
+                validation: None,
+                status: StatusCode::FORBIDDEN,
+            },
+            MyProblem::AuthAccountPendingDeletion => Problem {
+                id: MyProblem::AuthAccountPendingDeletion,
+                title: "Account pending deletion",
+                detail: "The deletion of this account was requested. Use the link sent by email to cancel it.".into(),
+                validation: None,
+                status: StatusCode::FORBIDDEN,
+            },
+            MyProblem::AuthMfaRequired(mfa_token) => Problem {
+                id: MyProblem::AuthMfaRequired(mfa_token),
+                title: "Two-factor authentication required",
+                detail: "The provided credentials are valid but a second factor is required. Send the provided `mfa_token` along with a TOTP code or a recovery code to finish logging in.".into(),
+                validation: None,
+                status: StatusCode::UNAUTHORIZED,
+            },
+            MyProblem::AuthInvalidMfaCode => Problem {
+                id: MyProblem::AuthInvalidMfaCode,
+                title: "Invalid two-factor authentication code",
+                detail: "The provided code is invalid or expired.".into(),
+                validation: None,
+                status: StatusCode::FORBIDDEN,
+            },
+            MyProblem::AuthTotpAlreadyEnabled => Problem {
+                id: MyProblem::AuthTotpAlreadyEnabled,
+                title: "Two-factor authentication is already enabled",
+                detail: "Disable two-factor authentication before enrolling a new authenticator.".into(),
+                validation: None,
+                status: StatusCode::CONFLICT,
+            },
+            MyProblem::AuthWebauthnFailed => Problem {
+                id: MyProblem::AuthWebauthnFailed,
+                title: "Passkey verification failed",
+                detail: "The passkey ceremony could not be completed. It may have expired or the authenticator response is invalid; start over.".into(),
+                validation: None,
+                status: StatusCode::BAD_REQUEST,
+            },
+            MyProblem::AuthTooManyAttempts(retry_after) => Problem {
+                id: MyProblem::AuthTooManyAttempts(retry_after),
+                title: "Too many attempts",
+                detail: format!("Too many attempts were made for this account or from this IP address. Retry in {retry_after} seconds.").into(),
+                validation: None,
+                status: StatusCode::TOO_MANY_REQUESTS,
+            },
 
             
 
             // Generics errors
+            MyProblem::TooManyRequests(retry_after) => Problem {
+                id: MyProblem::TooManyRequests(retry_after),
+                title: "Too many requests",
+                detail: format!("The rate limit was exceeded. Retry in {retry_after} seconds.").into(),
+                validation: None,
+                status: StatusCode::TOO_MANY_REQUESTS,
+            },
             MyProblem::Validation(e) => {
                 let errors_str = e.to_string();
                 Problem {
@@ -228,6 +401,27 @@ impl From<MyProblem> for Problem {
                 validation: None,
                 status: StatusCode::NOT_FOUND,
             },
+            MyProblem::Conflict => Problem {
+                id: MyProblem::Conflict,
+                title: "Conflict",
+                detail: "The request conflicts with the current state of the resource.".into(),
+                validation: None,
+                status: StatusCode::CONFLICT,
+            },
+            MyProblem::UnprocessableContent => Problem {
+                id: MyProblem::UnprocessableContent,
+                title: "Provided input is invalid",
+                detail: "The provided input does not satisfy the constraints of the resource.".into(),
+                validation: None,
+                status: StatusCode::UNPROCESSABLE_ENTITY,
+            },
+            MyProblem::ServiceUnavailable(retry_after) => Problem {
+                id: MyProblem::ServiceUnavailable(retry_after),
+                title: "Service unavailable",
+                detail: format!("The service is temporarily unavailable. Retry in {retry_after} seconds.").into(),
+                validation: None,
+                status: StatusCode::SERVICE_UNAVAILABLE,
+            },
             MyProblem::InternalServerError => Problem {
                 id: MyProblem::InternalServerError,
                 title: "Internal server error",
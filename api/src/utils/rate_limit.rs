@@ -0,0 +1,470 @@
+use actix_web::body::BoxBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use actix_web::Error;
+use biscuit_auth::{Biscuit, PrivateKey};
+use clap::ValueEnum;
+use futures_util::future::{ok, ready, BoxFuture, Ready};
+use futures_util::Future;
+use log::{error, trace};
+use sqlx::{query, PgPool};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use strum::EnumString;
+use uuid::Uuid;
+
+use crate::auth::iam::get_user_id;
+use crate::utils::client_info::ClientInfo;
+use crate::utils::problems::MyProblem;
+
+/// Number of calls to a store between two removals of the buckets that are full again
+const PRUNE_INTERVAL: u64 = 1000;
+
+/// What requests share a bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum RateLimitKey {
+    /// Client IP address
+    Ip,
+    /// User ID found in the biscuit of the request (falls back to the IP address for anonymous requests)
+    User,
+    /// Method and path of the request, whoever sends it
+    Route,
+}
+
+/// A token bucket holding `requests` tokens, refilled continuously at a rate of `requests` per `period`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub period: Duration,
+    pub key: RateLimitKey,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    /// Parse a rate limit written as `<requests>/<period in second>:<ip|user|route>`; for example `30/60:ip`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!("Rate limit '{s}' is invalid; expected `<requests>/<period in second>:<ip|user|route>`")
+        };
+
+        let (rate, key) = s.split_once(':').ok_or_else(invalid)?;
+        let (requests, period) = rate.split_once('/').ok_or_else(invalid)?;
+        let requests = requests.trim().parse::<u32>().map_err(|_| invalid())?;
+        let period = period.trim().parse::<u64>().map_err(|_| invalid())?;
+        let key = RateLimitKey::from_str(key.trim()).map_err(|_| invalid())?;
+
+        if requests == 0 || period == 0 {
+            Err(invalid())
+        } else {
+            Ok(RateLimit {
+                requests,
+                period: Duration::from_secs(period),
+                key,
+            })
+        }
+    }
+}
+
+impl RateLimit {
+    fn capacity(&self) -> f64 {
+        f64::from(self.requests)
+    }
+
+    /// Number of tokens added to the bucket every second
+    fn refill_rate(&self) -> f64 {
+        self.capacity() / self.period.as_secs_f64()
+    }
+
+    fn decision(&self, allowed: bool, tokens: f64) -> RateLimitDecision {
+        let rate = self.refill_rate();
+        RateLimitDecision {
+            allowed,
+            remaining: tokens.max(0.0).floor() as u32,
+            reset_after: ((self.capacity() - tokens) / rate).ceil().max(0.0) as u64,
+            retry_after: ((1.0 - tokens) / rate).ceil().max(1.0) as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RateLimitDecision {
+    allowed: bool,
+    remaining: u32,
+    /// Seconds until the bucket is full again
+    reset_after: u64,
+    /// Seconds until a request would be allowed again
+    retry_after: u64,
+}
+
+impl RateLimitDecision {
+    fn insert_headers(&self, headers: &mut HeaderMap, limit: &RateLimit) {
+        headers.insert(
+            HeaderName::from_static("ratelimit-limit"),
+            HeaderValue::from(limit.requests),
+        );
+        headers.insert(
+            HeaderName::from_static("ratelimit-remaining"),
+            HeaderValue::from(self.remaining),
+        );
+        headers.insert(
+            HeaderName::from_static("ratelimit-reset"),
+            HeaderValue::from(self.reset_after),
+        );
+        if let Ok(policy) = HeaderValue::from_str(&format!(
+            "{};w={}",
+            limit.requests,
+            limit.period.as_secs()
+        )) {
+            headers.insert(HeaderName::from_static("ratelimit-policy"), policy);
+        }
+    }
+}
+
+/// Where rate limiting counters are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RateLimitStoreKind {
+    /// In the memory of the current instance
+    Memory,
+    /// In the database, so that counters are shared between instances
+    Postgres,
+}
+
+pub trait RateLimitStore: Debug + Send + Sync {
+    /// Take a token from the bucket identified by `key` if there is one, and return whether a token was taken along with the number of tokens left
+    fn take<'a>(
+        &'a self,
+        key: &'a str,
+        limit: &'a RateLimit,
+    ) -> BoxFuture<'a, Result<(bool, f64), MyProblem>>;
+}
+
+#[derive(Debug)]
+struct MemoryBucket {
+    tokens: f64,
+    updated_at: Instant,
+    refilled_at: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryRateLimitStore {
+    buckets: Mutex<HashMap<String, MemoryBucket>>,
+    calls: AtomicU64,
+}
+
+impl RateLimitStore for MemoryRateLimitStore {
+    fn take<'a>(
+        &'a self,
+        key: &'a str,
+        limit: &'a RateLimit,
+    ) -> BoxFuture<'a, Result<(bool, f64), MyProblem>> {
+        Box::pin(ready(Ok(self.take_at(key, limit, Instant::now()))))
+    }
+}
+
+impl MemoryRateLimitStore {
+    /// Take a token from a bucket as if the request was made at `now`
+    fn take_at(&self, key: &str, limit: &RateLimit, now: Instant) -> (bool, f64) {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if self.calls.fetch_add(1, Ordering::Relaxed).is_multiple_of(PRUNE_INTERVAL) {
+            buckets.retain(|_, bucket| bucket.refilled_at > now);
+        }
+
+        let bucket = buckets.entry(key.to_owned()).or_insert(MemoryBucket {
+            tokens: limit.capacity(),
+            updated_at: now,
+            refilled_at: now,
+        });
+
+        let tokens = (bucket.tokens
+            + now.duration_since(bucket.updated_at).as_secs_f64() * limit.refill_rate())
+        .min(limit.capacity());
+        let allowed = tokens >= 1.0;
+
+        bucket.tokens = if allowed { tokens - 1.0 } else { tokens };
+        bucket.updated_at = now;
+        bucket.refilled_at = now + limit.period;
+
+        (allowed, bucket.tokens)
+    }
+}
+
+#[derive(Debug)]
+pub struct PostgresRateLimitStore {
+    db: PgPool,
+    calls: AtomicU64,
+}
+
+impl PostgresRateLimitStore {
+    pub fn new(db: PgPool) -> Self {
+        Self {
+            db,
+            calls: AtomicU64::new(0),
+        }
+    }
+}
+
+impl RateLimitStore for PostgresRateLimitStore {
+    fn take<'a>(
+        &'a self,
+        key: &'a str,
+        limit: &'a RateLimit,
+    ) -> BoxFuture<'a, Result<(bool, f64), MyProblem>> {
+        Box::pin(async move {
+            if self.calls.fetch_add(1, Ordering::Relaxed).is_multiple_of(PRUNE_INTERVAL) {
+                query!(
+                    "
+                        DELETE FROM infrastructure.rate_limit_bucket
+                        WHERE refilled_at <= statement_timestamp()
+                    "
+                )
+                .execute(&self.db)
+                .await?;
+            }
+
+            // The bucket is refilled and a token is taken in a single statement so that concurrent requests (possibly handled by other instances) cannot both take the last token
+            let bucket = query!(
+                r#"
+                    INSERT INTO infrastructure.rate_limit_bucket AS b (key, tokens, allowed, updated_at, refilled_at)
+                    VALUES ($1, $2::double precision - 1, true, statement_timestamp(), statement_timestamp() + make_interval(secs => $4))
+                    ON CONFLICT (key) DO UPDATE
+                    SET tokens = CASE
+                            WHEN LEAST($2, b.tokens + extract(epoch FROM statement_timestamp() - b.updated_at)::double precision * $3) >= 1
+                            THEN LEAST($2, b.tokens + extract(epoch FROM statement_timestamp() - b.updated_at)::double precision * $3) - 1
+                            ELSE LEAST($2, b.tokens + extract(epoch FROM statement_timestamp() - b.updated_at)::double precision * $3)
+                        END,
+                        allowed = LEAST($2, b.tokens + extract(epoch FROM statement_timestamp() - b.updated_at)::double precision * $3) >= 1,
+                        updated_at = statement_timestamp(),
+                        refilled_at = statement_timestamp() + make_interval(secs => $4)
+                    RETURNING tokens, allowed
+                "#,
+                key,
+                limit.capacity(),
+                limit.refill_rate(),
+                limit.period.as_secs_f64(),
+            )
+            .fetch_one(&self.db)
+            .await?;
+
+            Ok((bucket.allowed, bucket.tokens))
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    pub store: Arc<dyn RateLimitStore>,
+    pub limit: RateLimit,
+    /// Name of the group of routes this limiter applies to, so that each group has its own buckets
+    pub scope: &'static str,
+    pub biscuit_private_key: PrivateKey,
+    pub trust_proxy_headers: bool,
+}
+
+impl<S> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimiterMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        trace!("Initialize RateLimiterMiddleware ({})", self.scope);
+        ok(RateLimiterMiddleware {
+            service: Rc::new(service),
+            limiter: self.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimiterMiddleware<S> {
+    service: Rc<S>,
+    limiter: RateLimiter,
+}
+
+impl<S> RateLimiterMiddleware<S> {
+    fn bucket_key(&self, req: &ServiceRequest) -> String {
+        let scope = self.limiter.scope;
+        let ip_key = || {
+            let ip = ClientInfo::from_request(req.request(), self.limiter.trust_proxy_headers)
+                .ip
+                .map(|ip| ip.ip().to_string())
+                .unwrap_or_else(|| "unknown".to_owned());
+            format!("{scope}:ip:{ip}")
+        };
+
+        match self.limiter.limit.key {
+            RateLimitKey::Ip => ip_key(),
+            RateLimitKey::User => match self.user_id(req) {
+                Some(user_id) => format!("{scope}:user:{user_id}"),
+                None => ip_key(),
+            },
+            RateLimitKey::Route => format!("{scope}:route:{} {}", req.method(), req.path()),
+        }
+    }
+
+    /// Extract the user ID from the biscuit of the request; authorization is left to the `BiscuitAuth` middleware and to handlers
+    fn user_id(&self, req: &ServiceRequest) -> Option<Uuid> {
+        let token = req
+            .headers()
+            .get(AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .trim_start_matches("Bearer ");
+        let biscuit = Biscuit::from_base64(token, self.limiter.biscuit_private_key.public()).ok()?;
+        get_user_id(&biscuit).ok()
+    }
+}
+
+impl<S> Service<ServiceRequest> for RateLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let key = self.bucket_key(&req);
+        let store = Arc::clone(&self.limiter.store);
+        let limit = self.limiter.limit;
+        let srv = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            let decision = match store.take(&key, &limit).await {
+                Ok((allowed, tokens)) => limit.decision(allowed, tokens),
+                Err(e) => {
+                    // Failing to count requests must not make the whole API unavailable
+                    error!("Could not check rate limit of {key}: {e}");
+                    return srv.call(req).await;
+                }
+            };
+
+            let mut res = if decision.allowed {
+                srv.call(req).await?
+            } else {
+                let e = MyProblem::TooManyRequests(decision.retry_after);
+                trace!("{e} ({key})");
+                req.error_response(e)
+            };
+
+            decision.insert_headers(res.headers_mut(), &limit);
+            Ok(res)
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(requests: u32, period_in_s: u64) -> RateLimit {
+        RateLimit {
+            requests,
+            period: Duration::from_secs(period_in_s),
+            key: RateLimitKey::Ip,
+        }
+    }
+
+    #[test]
+    fn rate_limits_are_parsed() {
+        assert_eq!(RateLimit::from_str("30/60:ip"), Ok(limit(30, 60)));
+        assert_eq!(
+            RateLimit::from_str(" 5 / 10 : user "),
+            Ok(RateLimit {
+                requests: 5,
+                period: Duration::from_secs(10),
+                key: RateLimitKey::User,
+            })
+        );
+        assert_eq!(
+            RateLimit::from_str("100/1:route").map(|limit| limit.key),
+            Ok(RateLimitKey::Route)
+        );
+
+        for invalid in ["", "30/60", "30:ip", "0/60:ip", "30/0:ip", "-1/60:ip", "a/60:ip", "30/60:everyone"] {
+            assert!(RateLimit::from_str(invalid).is_err(), "{invalid} should be invalid");
+        }
+    }
+
+    #[test]
+    fn decisions_tell_when_the_bucket_is_refilled() {
+        // One token per second
+        let limit = limit(10, 10);
+
+        assert_eq!(
+            limit.decision(true, 4.0),
+            RateLimitDecision {
+                allowed: true,
+                remaining: 4,
+                reset_after: 6,
+                retry_after: 1,
+            }
+        );
+        assert_eq!(
+            limit.decision(false, 0.5),
+            RateLimitDecision {
+                allowed: false,
+                remaining: 0,
+                reset_after: 10,
+                retry_after: 1,
+            }
+        );
+
+        // One token every 30 seconds
+        let decision = self::limit(2, 60).decision(false, 0.0);
+        assert_eq!(decision.retry_after, 30);
+        assert_eq!(decision.reset_after, 60);
+    }
+
+    fn assert_take(store: &MemoryRateLimitStore, key: &str, at: Instant, expected: (bool, f64)) {
+        let (allowed, tokens) = store.take_at(key, &limit(2, 10), at);
+        assert_eq!(allowed, expected.0);
+        assert!((tokens - expected.1).abs() < 1e-9, "{tokens} tokens left instead of {}", expected.1);
+    }
+
+    #[test]
+    fn memory_store_denies_empty_buckets_until_they_are_refilled() {
+        // Buckets hold 2 tokens and get one every 5 seconds
+        let store = MemoryRateLimitStore::default();
+        let start = Instant::now();
+        let after = |seconds| start + Duration::from_secs(seconds);
+
+        assert_take(&store, "a", start, (true, 1.0));
+        assert_take(&store, "a", start, (true, 0.0));
+        assert_take(&store, "a", start, (false, 0.0));
+        assert_take(&store, "a", after(2), (false, 0.4));
+
+        // Other keys have their own bucket
+        assert_take(&store, "b", start, (true, 1.0));
+
+        assert_take(&store, "a", after(6), (true, 0.2));
+
+        // Buckets never hold more than their capacity
+        assert_take(&store, "a", after(60), (true, 1.0));
+    }
+}
\ No newline at end of file
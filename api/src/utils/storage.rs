@@ -0,0 +1,277 @@
+use clap::ValueEnum;
+use futures_util::future::{ready, BoxFuture};
+use log::error;
+use s3::creds::Credentials;
+use s3::error::S3Error;
+use s3::{Bucket, Region};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use url::Url;
+use uuid::Uuid;
+
+use crate::utils::problems::MyProblem;
+
+/// Where user uploads are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StorageKind {
+    /// In a directory of the local filesystem
+    Local,
+    /// In a bucket of an S3-compatible object storage, so that uploads are shared between instances
+    S3,
+    /// In the memory of the current instance; uploads are lost on restart
+    Memory,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredObject {
+    pub content: Vec<u8>,
+    pub content_type: String,
+}
+
+/// Blob storage; keys are `/`-separated relative paths such as `profile-pictures/{user_id}.jpeg`
+pub trait Storage: Debug + Send + Sync {
+    /// Create or replace an object; readers never see a partially written object
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        content: Vec<u8>,
+        content_type: &'a str,
+    ) -> BoxFuture<'a, Result<(), MyProblem>>;
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<StoredObject>, MyProblem>>;
+
+    /// Delete an object; deleting an object that does not exist is not an error
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), MyProblem>>;
+}
+
+#[derive(Debug)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_owned(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+/// Content types are not kept on disk, so they are guessed from the extension of the key
+fn content_type_from_key(key: &str) -> &'static str {
+    match Path::new(key).extension().and_then(|ext| ext.to_str()) {
+        Some("jpeg") | Some("jpg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some("json") => "application/json",
+        Some("zip") => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        content: Vec<u8>,
+        _content_type: &'a str,
+    ) -> BoxFuture<'a, Result<(), MyProblem>> {
+        Box::pin(async move {
+            let destination = self.path(key);
+            // Write to a temporary file first, then rename it over the destination
+            let temporary = destination.with_extension(format!("{}.tmp", Uuid::new_v4()));
+
+            let result = async {
+                if let Some(parent) = destination.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+                let mut file = fs::File::create(&temporary).await?;
+                file.write_all(&content).await?;
+                file.sync_all().await?;
+                fs::rename(&temporary, &destination).await
+            }
+            .await;
+
+            if let Err(e) = result {
+                let _ = fs::remove_file(&temporary).await;
+                error!("Error trying to write {}: {e}", destination.display());
+                return Err(MyProblem::InternalServerError);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<StoredObject>, MyProblem>> {
+        Box::pin(async move {
+            let path = self.path(key);
+            match fs::read(&path).await {
+                Ok(content) => Ok(Some(StoredObject {
+                    content,
+                    content_type: content_type_from_key(key).to_owned(),
+                })),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => {
+                    error!("Error trying to read {}: {e}", path.display());
+                    Err(MyProblem::InternalServerError)
+                }
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), MyProblem>> {
+        Box::pin(async move {
+            let path = self.path(key);
+            match fs::remove_file(&path).await {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+                Err(e) => {
+                    error!("Error trying to remove {}: {e}", path.display());
+                    Err(MyProblem::InternalServerError)
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct S3Storage {
+    bucket: Bucket,
+}
+
+impl S3Storage {
+    pub fn new(
+        endpoint: &Url,
+        region: &str,
+        bucket: &str,
+        access_key_id: &str,
+        secret_access_key: &str,
+    ) -> Result<Self, String> {
+        let credentials = Credentials::new(
+            Some(access_key_id),
+            Some(secret_access_key),
+            None,
+            None,
+            None,
+        )
+        .map_err(|e| format!("S3 credentials are invalid: {e}"))?;
+        let region = Region::Custom {
+            region: region.to_owned(),
+            endpoint: endpoint.as_str().trim_end_matches('/').to_owned(),
+        };
+
+        // Path-style URLs are supported by every S3-compatible storage, unlike virtual-hosted-style ones
+        let bucket = Bucket::new(bucket, region, credentials)
+            .map_err(|e| format!("S3 bucket is invalid: {e}"))?
+            .with_path_style();
+
+        Ok(Self { bucket })
+    }
+}
+
+impl Storage for S3Storage {
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        content: Vec<u8>,
+        content_type: &'a str,
+    ) -> BoxFuture<'a, Result<(), MyProblem>> {
+        Box::pin(async move {
+            self.bucket
+                .put_object_with_content_type(key, &content, content_type)
+                .await
+                .map_err(|e| {
+                    error!("Error trying to upload {key} to S3: {e}");
+                    MyProblem::InternalServerError
+                })?;
+
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<StoredObject>, MyProblem>> {
+        Box::pin(async move {
+            match self.bucket.get_object(key).await {
+                Ok(res) => Ok(Some(StoredObject {
+                    content_type: res
+                        .headers()
+                        .get("content-type")
+                        .cloned()
+                        .unwrap_or_else(|| content_type_from_key(key).to_owned()),
+                    content: res.bytes().to_vec(),
+                })),
+                Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+                Err(e) => {
+                    error!("Error trying to download {key} from S3: {e}");
+                    Err(MyProblem::InternalServerError)
+                }
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), MyProblem>> {
+        Box::pin(async move {
+            // S3 does not fail when deleting an object that does not exist
+            self.bucket.delete_object(key).await.map_err(|e| {
+                error!("Error trying to delete {key} from S3: {e}");
+                MyProblem::InternalServerError
+            })?;
+
+            Ok(())
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    objects: Mutex<HashMap<String, StoredObject>>,
+}
+
+impl MemoryStorage {
+    fn objects(&self) -> std::sync::MutexGuard<'_, HashMap<String, StoredObject>> {
+        self.objects
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        content: Vec<u8>,
+        content_type: &'a str,
+    ) -> BoxFuture<'a, Result<(), MyProblem>> {
+        self.objects().insert(
+            key.to_owned(),
+            StoredObject {
+                content,
+                content_type: content_type.to_owned(),
+            },
+        );
+
+        Box::pin(ready(Ok(())))
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<StoredObject>, MyProblem>> {
+        let object = self.objects().get(key).cloned();
+
+        Box::pin(ready(Ok(object)))
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), MyProblem>> {
+        self.objects().remove(key);
+
+        Box::pin(ready(Ok(())))
+    }
+}
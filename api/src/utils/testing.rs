@@ -0,0 +1,94 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use actix_web::test::TestRequest;
+use actix_web::web::ReqData;
+use actix_web::{FromRequest, HttpMessage};
+use biscuit_auth::{Biscuit, KeyPair};
+use chrono::Duration;
+use lettre::Address;
+use sqlx::{query, PgPool};
+use url::Url;
+use uuid::Uuid;
+
+use crate::auth::auth::{do_login, generate_hashed_password, lookup_user};
+use crate::auth::brute_force::AuthAttemptLimits;
+use crate::auth::webauthn::build_webauthn;
+use crate::utils::client_info::ClientInfo;
+use crate::utils::mail_transport::MemoryMailTransport;
+use crate::utils::mailer::Mailer;
+use crate::utils::storage::MemoryStorage;
+
+pub const PASSWORD: &str = "correct horse battery staple";
+
+/// Application state using the given test database and in-memory mail transport and storage
+pub fn state(db: PgPool) -> crate::State {
+    let app_url = Url::parse("https://app.example.com/").unwrap();
+
+    crate::State {
+        db,
+        biscuit_private_key: KeyPair::new().private(),
+        password_minimum_length: 12,
+        mailer: Mailer::new(
+            Arc::new(MemoryMailTransport::default()),
+            "Tauri Template".to_owned(),
+            Address::from_str("noreply@example.com").unwrap(),
+            Url::parse("https://example.com/logo.png").unwrap(),
+        )
+        .unwrap(),
+        webauthn: Arc::new(build_webauthn(&app_url).unwrap()),
+        app_url,
+        api_url: Url::parse("https://api.example.com/").unwrap(),
+        storage: Arc::new(MemoryStorage::default()),
+        trust_proxy_headers: false,
+        enumeration_protection: false,
+        auth_attempt_limits: AuthAttemptLimits {
+            max_failed_logins_per_account: 5,
+            max_failed_logins_per_ip: 20,
+            max_reset_password_emails_per_account: 3,
+            window: Duration::minutes(15),
+            lockout_duration: Duration::minutes(15),
+        },
+        account_deletion_grace_period: Duration::days(30),
+    }
+}
+
+/// Create a user with a verified email address, whose password is [`PASSWORD`]
+pub async fn create_user(db: &PgPool, email: &str) -> Uuid {
+    let user_id = Uuid::new_v4();
+    let password_hash = generate_hashed_password(PASSWORD).unwrap();
+
+    query!(
+        "
+            INSERT INTO iam.user (user__id, email, password, first_name, last_name, email_verified_at)
+            VALUES ($1, $2, $3, 'John', 'Doe', statement_timestamp())
+        ",
+        &user_id,
+        email,
+        password_hash.as_str(),
+    )
+    .execute(db)
+    .await
+    .unwrap();
+
+    user_id
+}
+
+/// Log the user in and return their access token, as the biscuit middleware passes it to handlers
+pub async fn user_biscuit(state: &crate::State, user_id: Uuid) -> ReqData<Biscuit> {
+    let user = lookup_user(&state.db, user_id).await.unwrap();
+    let req = TestRequest::default().to_http_request();
+    let client = ClientInfo::from_request(&req, false);
+    let login = do_login(&state.db, &state.biscuit_private_key, user, None, &client)
+        .await
+        .unwrap();
+
+    let access_token = serde_json::to_value(&login.0).unwrap()["access_token"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    let biscuit = Biscuit::from_base64(access_token, state.biscuit_private_key.public()).unwrap();
+    req.extensions_mut().insert(biscuit);
+
+    ReqData::<Biscuit>::extract(&req).await.unwrap()
+}
\ No newline at end of file
@@ -12,7 +12,8 @@ import { push } from "notivue";
 import { config } from "./lib/config";
 import type { components } from "@/types";
 import ProblemFactory from "@/utils/problemFactory";
-import { getAccessToken, getRefreshToken } from "@/iam";
+import { getAccessToken, getRefreshToken, refresh } from "@/iam";
+import i18n from "@/i18n";
 
 type definitions = components["schemas"];
 
@@ -25,12 +26,13 @@ async function getAxios(
       ? getRefreshToken().value
       : getAccessToken().value
     : null;
-  const headers =
-    token !== null
-      ? {
-          Authorization: `Bearer ${token}`,
-        }
-      : {};
+  const headers: Record<string, string> = {
+    // Emails sent by the API are written in the language of the interface
+    "Accept-Language": i18n.global.locale.value,
+  };
+  if (token !== null) {
+    headers.Authorization = `Bearer ${token}`;
+  }
 
   const client = axios.create({
     baseURL: config.API_ENDPOINT,
@@ -49,6 +51,20 @@ async function getAxios(
       );
     }
 
+    // Access tokens are rejected once the account was modified: get a new one and retry the request once
+    if (
+      authenticated &&
+      !use_refresh_token &&
+      error.config &&
+      (error.response?.data as Problem | undefined)?.id === "AuthStaleBiscuit"
+    ) {
+      const config = error.config;
+      return refresh().then(() => {
+        config.headers.set("Authorization", `Bearer ${getAccessToken().value}`);
+        return axios.request(config);
+      });
+    }
+
     return Promise.reject(error);
   });
 
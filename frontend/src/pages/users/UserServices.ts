@@ -2,17 +2,21 @@ import type { AxiosError, AxiosResponse } from "axios";
 import type { Problem } from "@/http.ts";
 import http, { handleError } from "@/http.ts";
 
-export async function deleteUser(): Promise<void> {
-  return http.delete("/user").then(
+export async function deleteUser(password: string): Promise<void> {
+  return http.delete("/user", { data: { password } }).then(
     (res: AxiosResponse<void>) => res.data,
     (err: AxiosError<AxiosResponse<Problem>>) =>
       Promise.reject(handleError(err)),
   );
 }
 
-export async function changePassword(new_password: string): Promise<void> {
+export async function changePassword(
+  current_password: string,
+  new_password: string,
+): Promise<void> {
   return http
     .post("/auth/password", {
+      current_password,
       new_password,
     })
     .then(
@@ -40,6 +44,34 @@ export async function resendVerificationEmail(token: string): Promise<void> {
     );
 }
 
+export async function confirmEmailChange(token: string): Promise<void> {
+  return http.unauthenticated
+    .post(`/auth/confirm-email-change`, { token })
+    .then(
+      (res: AxiosResponse<void>) => res.data,
+      (err: AxiosError<AxiosResponse<Problem>>) =>
+        Promise.reject(handleError(err)),
+    );
+}
+
+export async function undoEmailChange(token: string): Promise<void> {
+  return http.unauthenticated.post(`/auth/undo-email-change`, { token }).then(
+    (res: AxiosResponse<void>) => res.data,
+    (err: AxiosError<AxiosResponse<Problem>>) =>
+      Promise.reject(handleError(err)),
+  );
+}
+
+export async function cancelAccountDeletion(token: string): Promise<void> {
+  return http.unauthenticated
+    .post(`/auth/cancel-account-deletion`, { token })
+    .then(
+      (res: AxiosResponse<void>) => res.data,
+      (err: AxiosError<AxiosResponse<Problem>>) =>
+        Promise.reject(handleError(err)),
+    );
+}
+
 export async function beginResetPassword(email: string): Promise<void> {
   return http.unauthenticated
     .post(`/auth/begin-reset-password`, { email })
@@ -62,3 +94,11 @@ export async function resetPassword(
         Promise.reject(handleError(err)),
     );
 }
+
+export async function requestDataExport(): Promise<void> {
+  return http.post("/user/data-export").then(
+    () => undefined,
+    (err: AxiosError<AxiosResponse<Problem>>) =>
+      Promise.reject(handleError(err)),
+  );
+}
@@ -6,6 +6,9 @@ import UserSettings from "@/pages/users/UserSettings.vue";
 import Error404 from "@/pages/others/Error404.vue";
 import BeginResetpassword from "@/pages/users/BeginResetpassword.vue";
 import ResetPassword from "@/pages/users/ResetPassword.vue";
+import ConfirmEmailChange from "@/pages/users/ConfirmEmailChange.vue";
+import UndoEmailChange from "@/pages/users/UndoEmailChange.vue";
+import CancelAccountDeletion from "@/pages/users/CancelAccountDeletion.vue";
 
 export type TemplateRoutes = string;
 
@@ -17,6 +20,9 @@ export const routes: Record<TemplateRoutes, string> = {
   VerifyEmail: "VerifyEmail",
   BeginResetpassword: "BeginResetpassword",
   ResetPassword: "ResetPassword",
+  ConfirmEmailChange: "ConfirmEmailChange",
+  UndoEmailChange: "UndoEmailChange",
+  CancelAccountDeletion: "CancelAccountDeletion",
 
   Settings: "Settings",
   SecuritySettings: "SecuritySettings",
@@ -69,6 +75,33 @@ export default [
       redirectIfLoggedIn: false,
     },
   },
+  {
+    name: routes.ConfirmEmailChange,
+    path: "/confirm-email-change",
+    component: ConfirmEmailChange,
+    meta: {
+      requiresAuth: false,
+      redirectIfLoggedIn: false,
+    },
+  },
+  {
+    name: routes.UndoEmailChange,
+    path: "/undo-email-change",
+    component: UndoEmailChange,
+    meta: {
+      requiresAuth: false,
+      redirectIfLoggedIn: false,
+    },
+  },
+  {
+    name: routes.CancelAccountDeletion,
+    path: "/cancel-account-deletion",
+    component: CancelAccountDeletion,
+    meta: {
+      requiresAuth: false,
+      redirectIfLoggedIn: false,
+    },
+  },
   {
     name: routes.Settings,
     path: "/settings",
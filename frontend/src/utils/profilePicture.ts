@@ -0,0 +1,10 @@
+import http from "@/http";
+import type { UUID } from "@/http";
+
+// Profile pictures require an access token, so they cannot be loaded directly by an <img> element
+export async function fetchProfilePicture(userId: UUID): Promise<string> {
+  const res = await http.get<Blob>(`/user/${userId}/profile-picture`, {
+    responseType: "blob",
+  });
+  return URL.createObjectURL(res.data);
+}